@@ -8,6 +8,7 @@ use std::{
     borrow::Borrow,
     fmt,
     ops::{Deref, DerefMut},
+    str::FromStr,
 };
 
 // These values must be kept in sync with `arbutil/preimage_type.go`,
@@ -27,6 +28,58 @@ pub enum PreimageType {
 #[repr(C)]
 pub struct Bytes32(pub [u8; 32]);
 
+impl Bytes32 {
+    /// Parses 64 hex digits, with or without a `0x` prefix.
+    pub fn from_hex(text: &str) -> Result<Self, HexError> {
+        let digits = text.strip_prefix("0x").unwrap_or(text);
+        if digits.len() != 64 {
+            return Err(HexError::Length(digits.len()));
+        }
+        let mut bytes = Self::default();
+        hex::decode_to_slice(digits, &mut bytes.0).map_err(|err| match err {
+            hex::FromHexError::InvalidHexCharacter { c, index } => HexError::Char { c, index },
+            _ => HexError::Length(digits.len()),
+        })?;
+        Ok(bytes)
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self)
+    }
+
+    pub fn to_hex_prefixed(&self) -> String {
+        format!("0x{}", self.to_hex())
+    }
+}
+
+/// An error parsing a [`Bytes32`] from hex.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HexError {
+    /// The input had the wrong number of hex digits, not counting any `0x` prefix.
+    Length(usize),
+    /// The input contained a character that isn't a hex digit.
+    Char { c: char, index: usize },
+}
+
+impl fmt::Display for HexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Length(len) => write!(f, "expected 64 hex digits but got {len}"),
+            Self::Char { c, index } => write!(f, "invalid hex character {c:?} at index {index}"),
+        }
+    }
+}
+
+impl std::error::Error for HexError {}
+
+impl FromStr for Bytes32 {
+    type Err = HexError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        Self::from_hex(text)
+    }
+}
+
 impl Deref for Bytes32 {
     type Target = [u8; 32];
 
@@ -249,3 +302,31 @@ impl From<GenericBytes20> for Bytes20 {
         <[u8; 20]>::from(x).into()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{Bytes32, HexError};
+
+    #[test]
+    fn test_bytes32_hex() {
+        let hex = "00".repeat(31) + "2a";
+        let bytes = Bytes32::from(42u64);
+        assert_eq!(Bytes32::from_hex(&hex), Ok(bytes));
+        assert_eq!(Bytes32::from_hex(&format!("0x{hex}")), Ok(bytes));
+        assert_eq!(hex.parse::<Bytes32>(), Ok(bytes));
+        assert_eq!(bytes.to_hex(), hex);
+        assert_eq!(bytes.to_hex_prefixed(), format!("0x{hex}"));
+
+        assert_eq!(Bytes32::from_hex(&hex[1..]), Err(HexError::Length(63)));
+        assert_eq!(Bytes32::from_hex("0x"), Err(HexError::Length(0)));
+        assert_eq!(
+            Bytes32::from_hex(&(hex.clone() + "00")),
+            Err(HexError::Length(66))
+        );
+
+        let bad = "g".to_owned() + &hex[1..];
+        let err = Bytes32::from_hex(&bad).unwrap_err();
+        assert_eq!(err, HexError::Char { c: 'g', index: 0 });
+        assert_eq!(err.to_string(), "invalid hex character 'g' at index 0");
+    }
+}
@@ -0,0 +1,105 @@
+// Copyright 2024, Offchain Labs, Inc.
+// For license information, see https://github.com/OffchainLabs/nitro/blob/master/LICENSE
+
+use arbutil::crypto;
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use prover::prelude::{Bytes32, Merkle, MerkleType};
+
+/// Memory merkles are the largest trees the machine builds.
+const TY: MerkleType = MerkleType::Memory;
+const MIN_DEPTH: usize = 28;
+
+fn leaves(count: usize) -> Vec<Bytes32> {
+    (0..count as u64)
+        .map(|i| crypto::keccak(i.to_be_bytes()).into())
+        .collect()
+}
+
+fn tree(count: usize) -> Merkle {
+    Merkle::new_advanced(TY, leaves(count), Bytes32::default(), MIN_DEPTH)
+}
+
+fn construction(c: &mut Criterion) {
+    let mut group = c.benchmark_group("merkle/new");
+    group.sample_size(10);
+    for log in [10, 12, 14, 16, 18, 20] {
+        let count = 1 << log;
+        let hashes = leaves(count);
+        group.throughput(Throughput::Elements(count as u64));
+        group.bench_function(format!("2^{log}"), |b| {
+            b.iter_batched(
+                || hashes.clone(),
+                |hashes| Merkle::new_advanced(TY, hashes, Bytes32::default(), MIN_DEPTH),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn mutation(c: &mut Criterion) {
+    let count = 1 << 16;
+    let mut merkle = tree(count);
+    let mut group = c.benchmark_group("merkle/set");
+
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("single", |b| {
+        let mut i = 0;
+        b.iter(|| {
+            i = (i + 7919) % count;
+            merkle.set(i, Bytes32::from(i));
+            black_box(merkle.root())
+        })
+    });
+
+    group.throughput(Throughput::Elements(1000));
+    group.bench_function("batch_1000", |b| {
+        b.iter(|| {
+            for i in 0..1000 {
+                let idx = (i * 7919) % count;
+                merkle.set(idx, Bytes32::from(idx + i));
+            }
+            black_box(merkle.root())
+        })
+    });
+    group.finish();
+}
+
+fn proving(c: &mut Criterion) {
+    let count = 1 << 16;
+    let merkle = tree(count);
+    let mut group = c.benchmark_group("merkle/prove");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("2^16", |b| {
+        let mut i = 0;
+        b.iter(|| {
+            i = (i + 7919) % count;
+            black_box(merkle.prove(i))
+        })
+    });
+    group.finish();
+}
+
+fn growth(c: &mut Criterion) {
+    let mut group = c.benchmark_group("merkle/push_pop");
+    group.sample_size(10);
+    for log in [10, 14] {
+        let merkle = tree(1 << log);
+        group.throughput(Throughput::Elements(1));
+        group.bench_function(format!("2^{log}"), |b| {
+            b.iter_batched(
+                || merkle.clone(),
+                |mut merkle| {
+                    merkle.push_leaf(Bytes32::default());
+                    merkle.pop_leaf();
+                    merkle
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, construction, mutation, proving, growth);
+criterion_main!(benches);
@@ -0,0 +1,33 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use prover::merkle::Merkle;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(merkle) = bincode::deserialize::<Merkle>(data) else {
+        return;
+    };
+
+    // anything that deserializes must be safe to use
+    let leaves = merkle.leaves().len();
+    let _ = merkle.root();
+    let _ = merkle.prove(0);
+    let _ = merkle.prove_any(merkle.capacity().saturating_sub(1));
+    let mut merkle = merkle;
+    if leaves > 0 {
+        merkle.set(leaves - 1, Default::default());
+        let _ = merkle.root();
+    }
+
+    // as must reshaping it
+    let grow = data.last().copied().unwrap_or_default() % 16;
+    for _ in 0..grow {
+        merkle.push_leaf(Default::default());
+    }
+    merkle.truncate(leaves / 2);
+    let _ = merkle.root();
+    merkle.pop_leaf();
+    merkle.truncate(0);
+    merkle.push_leaf(Default::default());
+    let _ = merkle.prove(0);
+    let _ = merkle.root();
+});
@@ -0,0 +1,8 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use prover::Machine;
+
+fuzz_target!(|data: &[u8]| {
+    // malformed binaries must be rejected with an error, never a panic
+    let _ = Machine::new_from_wavm_bytes(data);
+});
@@ -0,0 +1,143 @@
+// Copyright 2024, Offchain Labs, Inc.
+// For license information, see https://github.com/OffchainLabs/nitro/blob/master/LICENSE
+
+//! Bridges async preimage stores into the synchronous [`PreimageResolver`] the machine steps with.
+
+use crate::{machine::PreimageResolver, utils::CBytes};
+use arbutil::{Bytes32, Color, PreimageType};
+use eyre::{eyre, Result};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{mpsc, Arc},
+    time::Duration,
+};
+use tokio::runtime::Handle;
+
+pub type ResolveFuture<'a> = Pin<Box<dyn Future<Output = Result<Option<CBytes>>> + Send + 'a>>;
+
+/// A preimage store that can only be queried asynchronously, such as one behind an HTTP API.
+pub trait AsyncPreimageResolver: Send + Sync + 'static {
+    fn resolve(&self, context: u64, ty: PreimageType, hash: Bytes32) -> ResolveFuture<'_>;
+}
+
+/// Resolves preimages synchronously by running an [`AsyncPreimageResolver`] on a tokio runtime.
+///
+/// Each request is spawned onto the runtime and the calling thread blocks until it completes or
+/// the timeout elapses, so this must not be used from a thread that is driving that runtime.
+pub struct AsyncResolverBridge<R> {
+    handle: Handle,
+    inner: Arc<R>,
+    timeout: Duration,
+}
+
+impl<R: AsyncPreimageResolver> AsyncResolverBridge<R> {
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+    pub fn new(handle: Handle, inner: R) -> Self {
+        Self {
+            handle,
+            inner: Arc::new(inner),
+            timeout: Self::DEFAULT_TIMEOUT,
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Resolves a preimage, erroring if the inner resolver fails or doesn't respond in time.
+    pub fn resolve(&self, context: u64, ty: PreimageType, hash: Bytes32) -> Result<Option<CBytes>> {
+        let (tx, rx) = mpsc::sync_channel(1);
+        let inner = self.inner.clone();
+        let task = self.handle.spawn(async move {
+            let result = inner.resolve(context, ty, hash).await;
+            let _ = tx.send(result);
+        });
+
+        match rx.recv_timeout(self.timeout) {
+            Ok(result) => result.map_err(|err| {
+                err.wrap_err(format!("failed to resolve {ty:?} preimage for hash {hash}"))
+            }),
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                task.abort();
+                Err(eyre!(
+                    "timed out after {:?} resolving {ty:?} preimage for hash {hash}",
+                    self.timeout
+                ))
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(eyre!(
+                "resolver task for {ty:?} preimage with hash {hash} was cancelled"
+            )),
+        }
+    }
+
+    /// Converts the bridge into a resolver the machine can use.
+    /// Since resolvers can't return errors, failures are printed and treated as missing preimages,
+    /// which the machine then reports with the hash it was looking for.
+    pub fn into_resolver(self) -> PreimageResolver {
+        Arc::new(
+            move |context, ty, hash| match self.resolve(context, ty, hash) {
+                Ok(preimage) => preimage,
+                Err(err) => {
+                    eprintln!("{} {err:?}", "Preimage resolution failed:".red());
+                    None
+                }
+            },
+        ) as PreimageResolver
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AsyncPreimageResolver, AsyncResolverBridge, ResolveFuture};
+    use crate::utils::CBytes;
+    use arbutil::{Bytes32, PreimageType};
+    use std::time::Duration;
+    use tokio::runtime::Builder;
+
+    /// Serves the hash itself as the preimage after a delay, or nothing for the zero hash.
+    struct MockResolver {
+        delay: Duration,
+    }
+
+    impl AsyncPreimageResolver for MockResolver {
+        fn resolve(&self, _: u64, _: PreimageType, hash: Bytes32) -> ResolveFuture<'_> {
+            Box::pin(async move {
+                tokio::time::sleep(self.delay).await;
+                if hash == Bytes32::default() {
+                    return Ok(None);
+                }
+                Ok(Some(CBytes::from(hash.as_slice())))
+            })
+        }
+    }
+
+    #[test]
+    fn test_async_bridge() {
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_time()
+            .build()
+            .unwrap();
+        let hash = Bytes32([7; 32]);
+        let ty = PreimageType::Keccak256;
+
+        let delay = Duration::from_millis(20);
+        let bridge = AsyncResolverBridge::new(runtime.handle().clone(), MockResolver { delay });
+        let preimage = bridge.resolve(0, ty, hash).unwrap().unwrap();
+        assert_eq!(preimage.as_slice(), hash.as_slice());
+        assert!(bridge.resolve(0, ty, Bytes32::default()).unwrap().is_none());
+
+        let delay = Duration::from_secs(60);
+        let bridge = AsyncResolverBridge::new(runtime.handle().clone(), MockResolver { delay })
+            .with_timeout(Duration::from_millis(20));
+        let err = bridge.resolve(0, ty, hash).unwrap_err().to_string();
+        assert!(err.contains("timed out"), "{err}");
+        assert!(err.contains(&hash.to_string()), "{err}");
+
+        let resolver = bridge.into_resolver();
+        assert!(resolver(0, ty, hash).is_none());
+    }
+}
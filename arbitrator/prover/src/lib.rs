@@ -3,6 +3,8 @@
 
 #![allow(clippy::missing_safety_doc, clippy::too_many_arguments)]
 
+#[cfg(feature = "async-resolver")]
+pub mod async_resolver;
 pub mod binary;
 mod host;
 #[cfg(feature = "native")]
@@ -10,7 +12,8 @@ mod kzg;
 pub mod machine;
 /// cbindgen:ignore
 mod memory;
-mod merkle;
+pub mod merkle;
+pub mod prelude;
 mod print;
 pub mod programs;
 mod reinterpret;
@@ -22,6 +25,10 @@ pub mod wavm;
 mod test;
 
 pub use machine::Machine;
+pub use memory::{memory_leaf_hash, verify_memory_word};
+pub use utils::{
+    hash_parallelism, run_guarded, set_hash_thread_pool, ProverPanic, ThreadPoolConfig,
+};
 
 use arbutil::{Bytes32, PreimageType};
 use eyre::{Report, Result};
@@ -36,6 +43,7 @@ use std::{
     ffi::CStr,
     num::NonZeroUsize,
     os::raw::{c_char, c_int},
+    panic::AssertUnwindSafe,
     path::Path,
     ptr, slice,
     sync::{
@@ -181,9 +189,11 @@ pub unsafe extern "C" fn arbitrator_step(
             break;
         }
         let stepping = std::cmp::min(remaining_steps, 1_000_000);
-        match mach.step_n(stepping) {
-            Ok(()) => {}
-            Err(err) => return err_to_c_string(err),
+        // unwinding across the FFI boundary would abort the embedding process
+        match run_guarded(AssertUnwindSafe(|| mach.step_n(stepping))) {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => return err_to_c_string(err),
+            Err(panic) => return err_to_c_string(panic.into()),
         }
         remaining_steps -= stepping;
     }
@@ -9,10 +9,10 @@ use crate::{
     },
     host,
     memory::Memory,
-    merkle::{Merkle, MerkleType},
+    merkle::{Merkle, MerkleType, ProveError},
     programs::{config::CompileConfig, meter::MeteredMachine, ModuleMod, StylusData},
     reinterpret::{ReinterpretAsSigned, ReinterpretAsUnsigned},
-    utils::{file_bytes, CBytes, RemoteTableType},
+    utils::{self, file_bytes, CBytes, ProverPanic, RemoteTableType},
     value::{ArbValueType, FunctionType, IntegerValType, ProgramCounter, Value},
     wavm::{
         self, pack_cross_module_call, unpack_cross_module_call, wasm_to_wavm, FloatingPointImpls,
@@ -34,6 +34,7 @@ use sha3::Keccak256;
 use smallvec::SmallVec;
 use std::{
     borrow::Cow,
+    collections::VecDeque,
     convert::{TryFrom, TryInto},
     fmt::{self, Display},
     fs::File,
@@ -42,7 +43,11 @@ use std::{
     num::Wrapping,
     ops::Add,
     path::{Path, PathBuf},
-    sync::Arc,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
 };
 use wasmer_types::FunctionIndex;
 use wasmparser::{DataKind, ElementItems, ElementKind, Operator, RefType, TableType};
@@ -50,6 +55,11 @@ use wasmparser::{DataKind, ElementItems, ElementKind, Operator, RefType, TableTy
 #[cfg(feature = "rayon")]
 use rayon::prelude::*;
 
+#[cfg(feature = "step-journal")]
+use fnv::FnvHasher;
+#[cfg(feature = "step-journal")]
+use std::{hash::Hasher, io::Read};
+
 fn hash_call_indirect_data(table: u32, ty: &FunctionType) -> Bytes32 {
     let mut h = Keccak256::new();
     h.update("Call indirect:");
@@ -153,11 +163,15 @@ impl Function {
         let chunks = math::div_ceil::<64>(code.len());
         let crunch = |x: usize| Instruction::hash(&code[64 * x..(64 * (x + 1)).min(code.len())]);
 
-        #[cfg(feature = "rayon")]
-        let code_hashes = (0..chunks).into_par_iter().map(crunch).collect();
+        let code_hashes = utils::with_hash_pool(|| {
+            #[cfg(feature = "rayon")]
+            let chunks = (0..chunks).into_par_iter();
 
-        #[cfg(not(feature = "rayon"))]
-        let code_hashes = (0..chunks).map(crunch).collect();
+            #[cfg(not(feature = "rayon"))]
+            let chunks = 0..chunks;
+
+            chunks.map(crunch).collect()
+        });
 
         self.code_merkle = Merkle::new(MerkleType::Instruction, code_hashes);
     }
@@ -611,6 +625,47 @@ impl Module {
         Ok(*func.1)
     }
 
+    /// Checks the invariants [`Module::from_binary`] establishes, which a module deserialized
+    /// from untrusted bytes may not uphold.
+    fn validate(&self) -> Result<()> {
+        let funcs = self.funcs.len();
+        ensure!(funcs > 0, "Module has no code");
+        ensure!(
+            funcs < (1usize << 31),
+            "Module function count must be under 2^31",
+        );
+        for func in self.funcs.iter() {
+            ensure!(
+                u32::try_from(func.code.len()).is_ok(),
+                "Function instruction count doesn't fit in a u32",
+            );
+        }
+        ensure!(
+            self.internals_offset as usize <= funcs,
+            "Module internals offset {} is past its {funcs} functions",
+            self.internals_offset,
+        );
+        if let Some(start) = self.start_function {
+            ensure!((start as usize) < funcs, "Start function {start} not found");
+        }
+        for (name, &func) in self.func_exports.iter() {
+            ensure!(
+                (func as usize) < funcs,
+                "Export {name} refers to missing func {func}",
+            );
+        }
+
+        let allowed = u32::MAX as u64 / Memory::PAGE_SIZE - 1;
+        let memory = &self.memory;
+        ensure!(
+            memory.max_size <= allowed && memory.size() <= memory.max_size * Memory::PAGE_SIZE,
+            "Memory of size {} exceeds its max of {} pages",
+            memory.size(),
+            memory.max_size,
+        );
+        Ok(())
+    }
+
     pub fn hash(&self) -> Bytes32 {
         let mut h = Keccak256::new();
         h.update("Module:");
@@ -788,7 +843,26 @@ pub struct GlobalState {
     pub u64_vals: [u64; GLOBAL_STATE_U64_NUM],
 }
 
+/// Where a machine is in the sequencer inbox, as recorded in its global state.
+///
+/// There's no delayed inbox counterpart: the number of delayed messages read is kept in the
+/// last L2 block header, which only the program in the machine's memory can interpret.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InboxPosition {
+    /// The number of sequencer messages fully consumed
+    pub sequencer_consumed: u64,
+    /// The position within the current sequencer message
+    pub pos_in_current: u64,
+}
+
 impl GlobalState {
+    pub fn inbox_position(&self) -> InboxPosition {
+        InboxPosition {
+            sequencer_consumed: self.u64_vals[0],
+            pos_in_current: self.u64_vals[1],
+        }
+    }
+
     fn hash(&self) -> Bytes32 {
         let mut h = Keccak256::new();
         h.update("Global state:");
@@ -899,12 +973,308 @@ pub struct MachineState<'a> {
 
 pub type PreimageResolver = Arc<dyn Fn(u64, PreimageType, Bytes32) -> Option<CBytes> + Send + Sync>;
 
+/// Statistics about the preimages a machine has resolved. Not part of the machine hash.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PreimageStats {
+    /// Number of preimages fetched from the resolver (cache hits excluded)
+    pub resolutions: u64,
+    /// Total bytes served by the resolver
+    pub bytes: u64,
+    /// Size of the largest preimage served
+    pub max_size: u64,
+}
+
+impl PreimageStats {
+    fn record(&mut self, size: usize) {
+        let size = size as u64;
+        self.resolutions += 1;
+        self.bytes += size;
+        self.max_size = self.max_size.max(size);
+    }
+}
+
+/// Statistics about a machine's execution. Not part of the machine hash.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MachineStats {
+    pub preimage: PreimageStats,
+}
+
+/// Which modules keep their memory merkle trees cached between steps.
+/// Hashes are identical under every policy, which only decides when trees are computed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MerkleizePolicy {
+    All,
+    None,
+    /// Caches the given modules' memories. The tree of module hashes is only cached once every
+    /// module's memory is, since keeping it current would rehash uncached memories on each flush.
+    Only(Vec<u32>),
+}
+
+impl FromStr for MerkleizePolicy {
+    type Err = eyre::Report;
+
+    /// Parses `all`, `none`, or a comma-separated list of module indices.
+    fn from_str(text: &str) -> Result<Self> {
+        match text {
+            "all" => Ok(Self::All),
+            "none" => Ok(Self::None),
+            _ => {
+                let modules: Result<_, _> = text.split(',').map(|x| x.trim().parse()).collect();
+                let modules = modules.wrap_err_with(|| format!("bad merkleize policy {text}"))?;
+                Ok(Self::Only(modules))
+            }
+        }
+    }
+}
+
+/// Stops a [`Machine::step_n_cancellable`] in progress, possibly from another thread.
+/// Clones share the same flag.
+#[derive(Clone, Debug)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+    check_every: u64,
+}
+
+impl CancelToken {
+    pub const DEFAULT_CHECK_EVERY: u64 = 4096;
+
+    pub fn new() -> Self {
+        Self::with_check_interval(Self::DEFAULT_CHECK_EVERY)
+    }
+
+    /// Checks for cancellation every `check_every` steps rather than the default.
+    pub fn with_check_interval(check_every: u64) -> Self {
+        assert_ne!(
+            check_every, 0,
+            "cancellation check interval must be positive"
+        );
+        Self {
+            cancelled: Arc::new(AtomicBool::new(false)),
+            check_every,
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for CancelToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How a [`Machine::step_n_cancellable`] ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StepOutcome {
+    /// All the requested steps ran, or the machine halted first.
+    Completed,
+    /// The token was cancelled after `steps` of the requested steps ran.
+    Cancelled { steps: u64 },
+}
+
+/// A machine hash recorded by [`Machine::enable_checkpoints`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub step: u64,
+    pub hash: Bytes32,
+}
+
+#[derive(Clone, Debug)]
+struct Checkpoints {
+    every: u64,
+    keep: usize,
+    recorded: VecDeque<Checkpoint>,
+    /// Full copies of the machine at each checkpoint, kept only for reverse stepping.
+    snapshots: Option<VecDeque<Machine>>,
+}
+
+/// Unwraps a proof of the machine's own state, which it should always be able to make,
+/// raising a [`ProverPanic`] of the given kind otherwise.
+fn expect_proof(
+    proof: Result<Vec<u8>, ProveError>,
+    panic: fn(String) -> ProverPanic,
+    what: &str,
+) -> Vec<u8> {
+    proof.unwrap_or_else(|err| panic(format!("{what}: {err}")).raise())
+}
+
+/// Proves the contents of the 32-byte memory leaf containing an address.
+///
+/// The leaf hashes to `keccak("Memory leaf:" ++ leaf)`, which together with `proof`
+/// and `leaf_index` reconstructs `memory_root` as with any other merkle proof.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemoryWordProof {
+    pub leaf_index: usize,
+    pub leaf: [u8; Memory::LEAF_SIZE],
+    pub proof: Vec<u8>,
+    pub memory_root: Bytes32,
+}
+
+/// A compact per-step record of execution for differential testing of the interpreter.
+/// Each step is summarized as an FNV digest of its program counter, opcode, top of stack,
+/// and the memory it writes, which is far cheaper than hashing the machine.
+#[cfg(feature = "step-journal")]
+#[derive(Clone, Debug, Default)]
+pub struct StepJournal {
+    /// The step the first buffered digest is for
+    start: u64,
+    digests: Vec<u64>,
+}
+
+#[cfg(feature = "step-journal")]
+impl StepJournal {
+    fn record(&mut self, pc: ProgramCounter, opcode: Opcode, top: Option<&Value>) {
+        let mut h = FnvHasher::default();
+        h.write_u32(pc.module);
+        h.write_u32(pc.func);
+        h.write_u32(pc.inst);
+        h.write_u16(opcode.repr());
+        if let Some(top) = top {
+            h.write(&top.serialize_for_proof());
+        }
+        self.digests.push(h.finish());
+    }
+
+    /// Folds a memory write made by the current step into its digest.
+    fn record_write(&mut self, ptr: u64, data: &[u8]) {
+        let Some(digest) = self.digests.last_mut() else {
+            return;
+        };
+        let mut h = FnvHasher::with_key(*digest);
+        h.write_u64(ptr);
+        h.write(data);
+        *digest = h.finish();
+    }
+
+    /// The step the first buffered digest is for.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// The buffered digests, one per step.
+    pub fn digests(&self) -> &[u64] {
+        &self.digests
+    }
+
+    /// Appends the buffered digests to `writer` as little-endian u64s, then clears the buffer.
+    pub fn flush(&mut self, mut writer: impl Write) -> Result<()> {
+        let bytes: Vec<u8> = self.digests.iter().flat_map(|x| x.to_le_bytes()).collect();
+        writer.write_all(&bytes)?;
+        self.start += self.digests.len() as u64;
+        self.digests.clear();
+        Ok(())
+    }
+
+    /// Reads digests previously written by [`StepJournal::flush`].
+    pub fn read(mut reader: impl Read) -> Result<Vec<u64>> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes)?;
+        ensure!(bytes.len() % 8 == 0, "journal length isn't a multiple of 8");
+        let chunks = bytes.chunks_exact(8);
+        Ok(chunks
+            .map(|x| u64::from_le_bytes(x.try_into().unwrap()))
+            .collect())
+    }
+}
+
+/// Finds the first step at which two journals started at the same step differ,
+/// including where one ends before the other.
+#[cfg(feature = "step-journal")]
+pub fn journal_diff(a: &[u64], b: &[u64]) -> Option<u64> {
+    let first = a.iter().zip(b).position(|(a, b)| a != b);
+    match first {
+        Some(step) => Some(step as u64),
+        None if a.len() != b.len() => Some(a.len().min(b.len()) as u64),
+        None => None,
+    }
+}
+
+/// A class of opcodes with similar costs to execute and prove.
+#[cfg(feature = "work-accounting")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WorkCategory {
+    /// Arithmetic, constants, locals, and globals
+    Compute,
+    /// Calls, returns, jumps, and thread switches
+    Control,
+    /// Loads, stores, and memory resizing
+    Memory,
+    /// Global state, inbox, preimage, and module linking operations
+    HostIo,
+}
+
+#[cfg(feature = "work-accounting")]
+impl WorkCategory {
+    pub const ALL: [Self; 4] = [Self::Compute, Self::Control, Self::Memory, Self::HostIo];
+
+    pub fn of(opcode: Opcode) -> Self {
+        use Opcode::*;
+        match opcode {
+            Return
+            | Call
+            | CallIndirect
+            | InitFrame
+            | ArbitraryJump
+            | ArbitraryJumpIf
+            | CrossModuleCall
+            | CrossModuleForward
+            | CrossModuleInternalCall
+            | CallerModuleInternalCall
+            | HaltAndSetFinished
+            | NewCoThread
+            | PopCoThread
+            | SwitchThread
+            | Unreachable => Self::Control,
+            MemoryLoad { .. } | MemoryStore { .. } | MemorySize | MemoryGrow => Self::Memory,
+            GetGlobalStateBytes32
+            | SetGlobalStateBytes32
+            | GetGlobalStateU64
+            | SetGlobalStateU64
+            | ReadPreImage
+            | ReadInboxMessage
+            | LinkModule
+            | UnlinkModule => Self::HostIo,
+            _ => Self::Compute,
+        }
+    }
+
+    /// The relative cost of one step in this category. Memory and host io steps require
+    /// merkle proofs of memory or external data, making them much more expensive to prove.
+    pub fn weight(self) -> u64 {
+        match self {
+            Self::Compute => 1,
+            Self::Control => 2,
+            Self::Memory => 4,
+            Self::HostIo => 64,
+        }
+    }
+}
+
+/// The work a machine performed since [`Machine::start_work_accounting`], weighted by category.
+#[cfg(feature = "work-accounting")]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WorkReport {
+    pub steps: u64,
+    pub weighted_cost: u64,
+    /// The weighted cost attributed to each category
+    pub per_category: Vec<(String, u64)>,
+}
+
 /// Wraps a preimage resolver to provide an easier API
 /// and cache the last preimage retrieved.
 #[derive(Clone)]
-struct PreimageResolverWrapper {
+pub(crate) struct PreimageResolverWrapper {
     resolver: PreimageResolver,
     last_resolved: Option<(Bytes32, CBytes)>,
+    /// Rehash each resolved preimage and check it against the requested hash.
+    verify: bool,
+    stats: PreimageStats,
 }
 
 impl fmt::Debug for PreimageResolverWrapper {
@@ -918,21 +1288,37 @@ impl PreimageResolverWrapper {
         PreimageResolverWrapper {
             resolver,
             last_resolved: None,
+            verify: cfg!(debug_assertions),
+            stats: PreimageStats::default(),
         }
     }
 
+    pub fn stats(&self) -> PreimageStats {
+        self.stats
+    }
+
+    pub fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+    }
+
     #[cfg(feature = "native")]
-    pub fn get(&mut self, context: u64, ty: PreimageType, hash: Bytes32) -> Option<&[u8]> {
+    pub fn get(&mut self, context: u64, ty: PreimageType, hash: Bytes32) -> Result<Option<&[u8]>> {
         // TODO: this is unnecessarily complicated by the rust borrow checker.
         // This will probably be simplifiable when Polonius is shipped.
         if matches!(&self.last_resolved, Some(r) if r.0 != hash) {
             self.last_resolved = None;
         }
         match &mut self.last_resolved {
-            Some(resolved) => Some(&resolved.1),
+            Some(resolved) => Ok(Some(&resolved.1)),
             x => {
-                let data = (self.resolver)(context, ty, hash)?;
-                Some(&x.insert((hash, data)).1)
+                let Some(data) = (self.resolver)(context, ty, hash) else {
+                    return Ok(None);
+                };
+                if self.verify {
+                    verify_preimage(&data, ty, hash)?;
+                }
+                self.stats.record(data.len());
+                Ok(Some(&x.insert((hash, data)).1))
             }
         }
     }
@@ -948,6 +1334,19 @@ impl PreimageResolverWrapper {
     }
 }
 
+/// Checks that a resolved preimage rehashes to the hash it was requested by.
+#[cfg(feature = "native")]
+pub(crate) fn verify_preimage(preimage: &[u8], ty: PreimageType, hash: Bytes32) -> Result<()> {
+    let have = utils::hash_preimage(preimage, ty)
+        .wrap_err_with(|| format!("failed to hash {ty:?} preimage for hash {hash}"))?;
+    let have = Bytes32(have);
+    ensure!(
+        have == hash,
+        "resolved incorrect {ty:?} preimage for hash {hash} (rehashed to {have})",
+    );
+    Ok(())
+}
+
 #[derive(Clone, Debug)]
 pub struct Machine {
     steps: u64, // Not part of machine hash
@@ -969,6 +1368,11 @@ pub struct Machine {
     initial_hash: Bytes32,
     context: u64,
     debug_info: bool, // Not part of machine hash
+    #[cfg(feature = "step-journal")]
+    journal: Option<StepJournal>, // Not part of machine hash
+    checkpoints: Option<Checkpoints>, // Not part of machine hash
+    #[cfg(feature = "work-accounting")]
+    work: Option<[u64; WorkCategory::ALL.len()]>, // Not part of machine hash
 }
 
 type FrameStackHash = Bytes32;
@@ -1517,6 +1921,7 @@ impl Machine {
                 MerkleType::Module,
                 modules.iter().map(Module::hash).collect(),
             ));
+            log::debug!("merkleized {} modules", modules.len());
         }
 
         // find the first inbox index that's out of bounds
@@ -1546,21 +1951,45 @@ impl Machine {
             initial_hash: Bytes32::default(),
             context: 0,
             debug_info,
+            #[cfg(feature = "step-journal")]
+            journal: None,
+            checkpoints: None,
+            #[cfg(feature = "work-accounting")]
+            work: None,
         };
         mach.initial_hash = mach.hash();
+        log::debug!(
+            "loaded {} with {} modules and initial hash {}",
+            mach.main_module_name(),
+            mach.modules.len(),
+            mach.initial_hash,
+        );
         Ok(mach)
     }
 
     pub fn new_from_wavm(wavm_binary: &Path) -> Result<Machine> {
+        let compressed = std::fs::read(wavm_binary)?;
+        let mach = Self::new_from_wavm_bytes(&compressed)
+            .wrap_err_with(|| format!("failed to load wavm binary at {wavm_binary:?}"))?;
+        log::debug!(
+            "loaded {wavm_binary:?} with initial hash {}",
+            mach.initial_hash
+        );
+        Ok(mach)
+    }
+
+    /// Loads a machine from the contents of a brotli-compressed wavm binary.
+    pub fn new_from_wavm_bytes(compressed: &[u8]) -> Result<Machine> {
         let mut modules: Vec<Module> = {
-            let compressed = std::fs::read(wavm_binary)?;
-            let Ok(modules) = brotli::decompress(&compressed, Dictionary::Empty) else {
+            let Ok(modules) = brotli::decompress(compressed, Dictionary::Empty) else {
                 bail!("failed to decompress wavm binary");
             };
             bincode::deserialize(&modules)?
         };
+        ensure!(!modules.is_empty(), "wavm binary has no modules");
 
         for module in modules.iter_mut() {
+            module.validate()?;
             for table in module.tables.iter_mut() {
                 table.elems_merkle = Merkle::new(
                     MerkleType::TableElement,
@@ -1570,7 +1999,9 @@ impl Machine {
             let tables: Result<_> = module.tables.iter().map(Table::hash).collect();
             module.tables_merkle = Merkle::new(MerkleType::Table, tables?);
 
-            let funcs = Arc::get_mut(&mut module.funcs).expect("Multiple copies of module funcs");
+            let Some(funcs) = Arc::get_mut(&mut module.funcs) else {
+                bail!("multiple copies of module funcs");
+            };
             funcs.iter_mut().for_each(Function::set_code_merkle);
 
             module.funcs_merkle = Arc::new(Merkle::new(
@@ -1597,6 +2028,11 @@ impl Machine {
             initial_hash: Bytes32::default(),
             context: 0,
             debug_info: false,
+            #[cfg(feature = "step-journal")]
+            journal: None,
+            checkpoints: None,
+            #[cfg(feature = "work-accounting")]
+            work: None,
         };
         mach.initial_hash = mach.hash();
         Ok(mach)
@@ -1646,6 +2082,7 @@ impl Machine {
         writer.flush()?;
         drop(writer);
         f.sync_data()?;
+        log::debug!("wrote machine snapshot at step {}", self.steps);
         Ok(())
     }
 
@@ -1675,6 +2112,7 @@ impl Machine {
         self.global_state = new_state.global_state;
         self.pc = new_state.pc;
         self.stdio_output = new_state.stdio_output.into_owned();
+        log::debug!("restored machine snapshot at step {}", self.steps);
         Ok(())
     }
 
@@ -1686,6 +2124,7 @@ impl Machine {
             MerkleType::Module,
             self.modules.iter().map(Module::hash).collect(),
         ));
+        log::debug!("merkleized {} modules", self.modules.len());
     }
 
     pub fn stop_merkle_caching(&mut self) {
@@ -1695,6 +2134,66 @@ impl Machine {
         }
     }
 
+    /// Caches memory merkle trees according to the policy, computing the rest lazily when hashing.
+    pub fn set_merkleize_policy(&mut self, policy: MerkleizePolicy) {
+        let modules = match policy {
+            MerkleizePolicy::All => return self.start_merkle_caching(),
+            MerkleizePolicy::None => return self.stop_merkle_caching(),
+            MerkleizePolicy::Only(modules) => modules,
+        };
+        for (i, module) in self.modules.iter_mut().enumerate() {
+            match modules.contains(&(i as u32)) {
+                true => module.memory.cache_merkle_tree(),
+                false => module.memory.merkle = None,
+            }
+        }
+        self.sync_modules_merkle();
+    }
+
+    pub fn with_merkleize_policy(mut self, policy: MerkleizePolicy) -> Self {
+        self.set_merkleize_policy(policy);
+        self
+    }
+
+    /// Starts or stops caching a single module's memory merkle tree.
+    pub fn set_module_merkleize(&mut self, module: u32, enabled: bool) -> Result<()> {
+        let Some(module) = self.modules.get_mut(module as usize) else {
+            bail!("no module at index {module}");
+        };
+        match enabled {
+            true => module.memory.cache_merkle_tree(),
+            false => module.memory.merkle = None,
+        }
+        self.sync_modules_merkle();
+        Ok(())
+    }
+
+    /// Whether a module's memory merkle tree is cached between steps.
+    pub fn is_module_merkleized(&self, module: u32) -> bool {
+        let module = self.modules.get(module as usize);
+        module.map_or(false, |x| x.memory.merkle.is_some())
+    }
+
+    /// Whether the tree of module hashes is cached between steps.
+    pub fn is_modules_merkle_cached(&self) -> bool {
+        self.modules_merkle.is_some()
+    }
+
+    /// Caches the tree of module hashes exactly when every module's memory tree is cached.
+    fn sync_modules_merkle(&mut self) {
+        let all_cached = self.modules.iter().all(|x| x.memory.merkle.is_some());
+        match (all_cached, self.modules_merkle.is_some()) {
+            (true, false) => {
+                self.modules_merkle = Some(Merkle::new(
+                    MerkleType::Module,
+                    self.modules.iter().map(Module::hash).collect(),
+                ))
+            }
+            (false, true) => self.modules_merkle = None,
+            _ => {}
+        }
+    }
+
     pub fn main_module_name(&self) -> String {
         self.modules.last().expect("no module").name().to_owned()
     }
@@ -1703,6 +2202,54 @@ impl Machine {
         &self.modules.last().expect("no module").memory
     }
 
+    /// Proves the memory leaf containing `address` in the given module's memory.
+    /// Addresses past the end of the allocated memory prove an empty leaf.
+    pub fn prove_memory_word(&self, module: u32, address: u64) -> Result<MemoryWordProof> {
+        let Some(module) = self.modules.get(module as usize) else {
+            bail!("no module at index {module}");
+        };
+        let leaf_index = usize::try_from(address / Memory::LEAF_SIZE as u64)?;
+        let merkle = module.memory.merkelize();
+        let proof = merkle
+            .prove_any(leaf_index)
+            .wrap_err_with(|| format!("address {address:#x} is beyond the maximum memory size"))?;
+        Ok(MemoryWordProof {
+            leaf_index,
+            leaf: module.memory.get_leaf_data(leaf_index),
+            proof,
+            memory_root: merkle.root(),
+        })
+    }
+
+    /// Writes the given module's linear memory to a file, returning the number of bytes written.
+    pub fn dump_memory(&self, module: u32, path: &Path) -> Result<u64> {
+        let Some(module) = self.modules.get(module as usize) else {
+            bail!("no module at index {module}");
+        };
+        let memory = &module.memory;
+        let data = memory.get_range(0, memory.size() as usize).unwrap();
+        std::fs::write(path, data).wrap_err_with(|| format!("failed to write {path:?}"))?;
+        Ok(memory.size())
+    }
+
+    /// Replaces the given module's linear memory with the contents of a file, which must be a
+    /// whole number of pages within the memory's limits.
+    pub fn load_memory(&mut self, module: u32, path: &Path) -> Result<()> {
+        let index = module as usize;
+        let Some(module) = self.modules.get_mut(index) else {
+            bail!("no module at index {module}");
+        };
+        let data = std::fs::read(path).wrap_err_with(|| format!("failed to read {path:?}"))?;
+        module
+            .memory
+            .replace(data)
+            .wrap_err_with(|| format!("failed to load {path:?}"))?;
+        if let Some(merkle) = self.modules_merkle.as_mut() {
+            merkle.set(index, module.hash());
+        }
+        Ok(())
+    }
+
     pub fn main_module_hash(&self) -> Bytes32 {
         self.modules.last().expect("no module").hash()
     }
@@ -1879,7 +2426,47 @@ impl Machine {
     }
 
     #[cfg(feature = "native")]
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(n = n, steps = self.steps))
+    )]
     pub fn step_n(&mut self, n: u64) -> Result<()> {
+        let Some(every) = self.checkpoints.as_ref().map(|x| x.every) else {
+            return self.step_n_between_checkpoints(n);
+        };
+
+        // stop on each multiple of the cadence to record its checkpoint
+        let mut remaining = n;
+        while remaining > 0 && !self.is_halted() {
+            let chunk = remaining.min(every - self.steps % every);
+            self.step_n_between_checkpoints(chunk)?;
+            remaining -= chunk;
+            if self.steps % every == 0 {
+                self.record_checkpoint();
+            }
+        }
+        Ok(())
+    }
+
+    /// Like [`Machine::step_n`], but stops early once the token is cancelled, which is checked
+    /// every [`CancelToken::with_check_interval`] steps. A cancelled machine is left in a
+    /// consistent state and can be resumed by stepping the remainder.
+    #[cfg(feature = "native")]
+    pub fn step_n_cancellable(&mut self, n: u64, token: &CancelToken) -> Result<StepOutcome> {
+        let mut steps = 0;
+        while steps < n && !self.is_halted() {
+            if token.is_cancelled() {
+                return Ok(StepOutcome::Cancelled { steps });
+            }
+            let start = self.steps;
+            self.step_n(token.check_every.min(n - steps))?;
+            steps += self.steps - start;
+        }
+        Ok(StepOutcome::Completed)
+    }
+
+    #[cfg(feature = "native")]
+    fn step_n_between_checkpoints(&mut self, n: u64) -> Result<()> {
         if self.is_halted() {
             return Ok(());
         }
@@ -1943,6 +2530,19 @@ impl Machine {
             }};
         }
 
+        #[cfg(feature = "step-journal")]
+        macro_rules! journal_write {
+            ($ptr:expr, $data:expr) => {
+                if let Some(journal) = &mut self.journal {
+                    journal.record_write($ptr, $data);
+                }
+            };
+        }
+        #[cfg(not(feature = "step-journal"))]
+        macro_rules! journal_write {
+            ($ptr:expr, $data:expr) => {};
+        }
+
         for _ in 0..n {
             self.steps += 1;
             if self.steps == Self::MAX_STEPS {
@@ -1954,6 +2554,14 @@ impl Machine {
             }
 
             let inst = func.code[self.pc.inst()];
+            #[cfg(feature = "step-journal")]
+            if let Some(journal) = &mut self.journal {
+                journal.record(self.pc, inst.opcode, value_stack.last());
+            }
+            #[cfg(feature = "work-accounting")]
+            if let Some(work) = &mut self.work {
+                work[WorkCategory::of(inst.opcode) as usize] += 1;
+            }
             self.pc.inst += 1;
             match inst.opcode {
                 Opcode::Unreachable => error!("unreachable"),
@@ -2174,6 +2782,7 @@ impl Machine {
                     if !module.memory.store_value(idx, val, bytes) {
                         error!();
                     }
+                    journal_write!(idx, &val.to_le_bytes()[..bytes.into()]);
                 }
                 Opcode::I32Const => {
                     value_stack.push(Value::I32(inst.argument_data as u32));
@@ -2261,6 +2870,7 @@ impl Machine {
                     })();
                     if let Some(new_size) = new_size {
                         module.memory.resize(usize::try_from(new_size).unwrap());
+                        journal_write!(new_size, &[]);
                         // Push the old number of pages
                         let old_pages = u32::try_from(old_size / page_size).unwrap();
                         value_stack.push(old_pages.into());
@@ -2396,6 +3006,7 @@ impl Machine {
                     {
                         error!();
                     }
+                    journal_write!(ptr.into(), &*self.global_state.bytes32_vals[idx]);
                 }
                 Opcode::SetGlobalStateBytes32 => {
                     let ptr = value_stack.pop().unwrap().assume_u32();
@@ -2438,7 +3049,8 @@ impl Machine {
                         error!();
                     };
                     let Some(preimage) =
-                        self.preimage_resolver.get(self.context, preimage_ty, hash)
+                        self.preimage_resolver
+                            .get(self.context, preimage_ty, hash)?
                     else {
                         eprintln!(
                             "{} for hash {}",
@@ -2463,6 +3075,7 @@ impl Machine {
                     let read = preimage.get(offset..(offset + len)).unwrap_or_default();
                     let success = module.memory.store_slice_aligned(ptr.into(), read);
                     assert!(success, "Failed to write to previously read memory");
+                    journal_write!(ptr.into(), read);
                     value_stack.push(Value::I32(len as u32));
                 }
                 Opcode::ReadInboxMessage => {
@@ -2479,6 +3092,7 @@ impl Machine {
                             let len = std::cmp::min(32, message.len().saturating_sub(offset));
                             let read = message.get(offset..(offset + len)).unwrap_or_default();
                             if module.memory.store_slice_aligned(ptr.into(), read) {
+                                journal_write!(ptr.into(), read);
                                 value_stack.push(Value::I32(len as u32));
                             } else {
                                 error!();
@@ -2766,6 +3380,10 @@ impl Machine {
         (frame_stacks, value_stacks, inter_stack)
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(status = ?self.status))
+    )]
     pub fn hash(&self) -> Bytes32 {
         let mut h = Keccak256::new();
         match self.status {
@@ -2862,9 +3480,11 @@ impl Machine {
 
         // Prove module is in modules merkle tree
 
-        out!(mod_merkle
-            .prove(self.pc.module())
-            .expect("Failed to prove module"));
+        out!(expect_proof(
+            mod_merkle.prove(self.pc.module()),
+            ProverPanic::CorruptTree,
+            "Failed to prove module"
+        ));
 
         if self.is_halted() {
             return data;
@@ -2874,14 +3494,17 @@ impl Machine {
 
         let func = &module.funcs[self.pc.func()];
         out!(func.serialize_body_for_proof(self.pc));
-        out!(func
-            .code_merkle
-            .prove(self.pc.inst() / Function::CHUNK_SIZE)
-            .expect("Failed to prove against code merkle"));
-        out!(module
-            .funcs_merkle
-            .prove(self.pc.func())
-            .expect("Failed to prove against function merkle"));
+        out!(expect_proof(
+            func.code_merkle
+                .prove(self.pc.inst() / Function::CHUNK_SIZE),
+            ProverPanic::CorruptTree,
+            "Failed to prove against code merkle"
+        ));
+        out!(expect_proof(
+            module.funcs_merkle.prove(self.pc.func()),
+            ProverPanic::CorruptTree,
+            "Failed to prove against function merkle"
+        ));
 
         // End next instruction proof, begin instruction specific serialization
 
@@ -2905,14 +3528,22 @@ impl Machine {
                 out!(locals[idx].serialize_for_proof());
                 let merkle =
                     Merkle::new(MerkleType::Value, locals.iter().map(|v| v.hash()).collect());
-                out!(merkle.prove(idx).expect("Out of bounds local access"));
+                out!(expect_proof(
+                    merkle.prove(idx),
+                    ProverPanic::IndexOutOfBounds,
+                    "Out of bounds local access"
+                ));
             }
             GlobalGet | GlobalSet => {
                 let idx = arg as usize;
                 out!(module.globals[idx].serialize_for_proof());
                 let globals_merkle = module.globals.iter().map(|v| v.hash()).collect();
                 let merkle = Merkle::new(MerkleType::Value, globals_merkle);
-                out!(merkle.prove(idx).expect("Out of bounds global access"));
+                out!(expect_proof(
+                    merkle.prove(idx),
+                    ProverPanic::IndexOutOfBounds,
+                    "Out of bounds global access"
+                ));
             }
             MemoryLoad { .. } | MemoryStore { .. } => {
                 let is_store = matches!(op, MemoryStore { .. });
@@ -2969,27 +3600,31 @@ impl Machine {
                 out!(table
                     .serialize_for_proof()
                     .expect("failed to serialize table"));
-                out!(module
-                    .tables_merkle
-                    .prove(table_usize)
-                    .expect("Failed to prove tables merkle"));
+                out!(expect_proof(
+                    module.tables_merkle.prove(table_usize),
+                    ProverPanic::CorruptTree,
+                    "Failed to prove tables merkle"
+                ));
                 let idx_usize = usize::try_from(idx).unwrap();
                 if let Some(elem) = table.elems.get(idx_usize) {
                     out!(elem.func_ty.hash());
                     out!(elem.val.serialize_for_proof());
-                    out!(table
-                        .elems_merkle
-                        .prove(idx_usize)
-                        .expect("Failed to prove elements merkle"));
+                    out!(expect_proof(
+                        table.elems_merkle.prove(idx_usize),
+                        ProverPanic::CorruptTree,
+                        "Failed to prove elements merkle"
+                    ));
                 }
             }
             CrossModuleInternalCall => {
                 let module_idx = value_stack.last().unwrap().assume_u32() as usize;
                 let called_module = &self.modules[module_idx];
                 out!(called_module.serialize_for_proof(&called_module.memory.merkelize()));
-                out!(mod_merkle
-                    .prove(module_idx)
-                    .expect("Failed to prove module for CrossModuleInternalCall"));
+                out!(expect_proof(
+                    mod_merkle.prove(module_idx),
+                    ProverPanic::CorruptTree,
+                    "Failed to prove module for CrossModuleInternalCall"
+                ));
             }
             GetGlobalStateBytes32 | SetGlobalStateBytes32 => {
                 out!(self.global_state.serialize());
@@ -3071,7 +3706,11 @@ impl Machine {
                 // if needed, prove that x is the last module by proving that leaf p + 1 is 0
                 let balanced = math::is_power_of_2(leaf + 1);
                 if !balanced {
-                    out!(mod_merkle.prove_any(leaf + 1));
+                    out!(expect_proof(
+                        mod_merkle.prove_any(leaf + 1),
+                        ProverPanic::CorruptTree,
+                        "Failed to prove module merkle is unbalanced"
+                    ));
                 }
             }
             PopCoThread => {
@@ -3135,21 +3774,217 @@ impl Machine {
         self.global_state = gs;
     }
 
+    /// The machine's position in the sequencer inbox. Delayed messages are sequenced by
+    /// the replay program itself, so the machine has no count of those it's consumed.
+    pub fn inbox_position(&self) -> InboxPosition {
+        self.global_state.inbox_position()
+    }
+
     pub fn set_preimage_resolver(&mut self, resolver: PreimageResolver) {
         self.preimage_resolver.resolver = resolver;
     }
 
+    pub fn stats(&self) -> MachineStats {
+        MachineStats {
+            preimage: self.preimage_resolver.stats(),
+        }
+    }
+
+    /// Rehashes every resolved preimage, erroring if it doesn't match the requested hash.
+    /// Enabled by default in debug builds.
+    pub fn set_verify_preimages(&mut self, verify: bool) {
+        self.preimage_resolver.set_verify(verify);
+    }
+
+    /// Records the machine's hash every `every` steps, keeping the most recent `keep`,
+    /// so that bisection can start from a nearby checkpoint rather than from the beginning.
+    pub fn enable_checkpoints(&mut self, every: u64, keep: usize) {
+        assert_ne!(every, 0, "checkpoint cadence must be positive");
+        self.checkpoints = Some(Checkpoints {
+            every,
+            keep,
+            recorded: VecDeque::with_capacity(keep),
+            snapshots: None,
+        });
+    }
+
+    /// Like [`Machine::enable_checkpoints`], but also keeps a copy of the machine at each
+    /// checkpoint so that [`Machine::reverse_step_n`] can rewind. The current step is
+    /// snapshotted immediately.
+    ///
+    /// Each snapshot costs a full clone of the machine, so memory grows with `keep`,
+    /// while `every` bounds how many steps a rewind has to replay.
+    pub fn enable_reverse_stepping(&mut self, every: u64, keep: usize) {
+        self.enable_checkpoints(every, keep);
+        if let Some(checkpoints) = &mut self.checkpoints {
+            checkpoints.snapshots = Some(VecDeque::with_capacity(keep));
+        }
+        self.record_checkpoint();
+    }
+
+    /// Rewinds the machine by `n` steps by restoring the latest snapshot at or before the
+    /// target step and replaying forward from it. Requires [`Machine::enable_reverse_stepping`].
+    ///
+    /// Replay costs at most `every` steps, as long as the target is no older than the
+    /// retained snapshots. Inbox messages, preimages, and stylus modules added since the
+    /// snapshot are kept, while the journal and work counts are replayed along with the machine.
+    #[cfg(feature = "native")]
+    pub fn reverse_step_n(&mut self, n: u64) -> Result<()> {
+        let Some(target) = self.steps.checked_sub(n) else {
+            bail!("cannot reverse {n} steps from step {}", self.steps);
+        };
+        let Some(mut checkpoints) = self.checkpoints.take() else {
+            bail!("reverse stepping isn't enabled");
+        };
+        let Some(snapshots) = &mut checkpoints.snapshots else {
+            self.checkpoints = Some(checkpoints);
+            bail!("reverse stepping isn't enabled");
+        };
+        let Some(snapshot) = snapshots.iter().rev().find(|x| x.steps <= target) else {
+            self.checkpoints = Some(checkpoints);
+            bail!("no snapshot retained at or before step {target}");
+        };
+        let mut machine = snapshot.clone();
+
+        // later records are re-taken as the replay passes them
+        let step = machine.steps;
+        snapshots.retain(|x| x.steps <= step);
+        checkpoints.recorded.retain(|x| x.step <= step);
+
+        machine.inbox_contents = std::mem::take(&mut self.inbox_contents);
+        machine.first_too_far = self.first_too_far;
+        machine.preimage_resolver = self.preimage_resolver.clone();
+        machine.stylus_modules = std::mem::take(&mut self.stylus_modules);
+        machine.checkpoints = Some(checkpoints);
+        *self = machine;
+
+        // the replayed steps' preimages were already counted when first stepped over
+        let stats = self.preimage_resolver.stats();
+        let result = self.step_n(target - step);
+        self.preimage_resolver.stats = stats;
+        result
+    }
+
+    pub fn disable_checkpoints(&mut self) {
+        self.checkpoints = None;
+    }
+
+    /// The retained checkpoints, oldest first.
+    pub fn checkpoints(&self) -> impl Iterator<Item = Checkpoint> + '_ {
+        let recorded = self.checkpoints.iter().flat_map(|x| &x.recorded);
+        recorded.copied()
+    }
+
+    /// The latest retained checkpoint at or before the given step.
+    pub fn nearest_checkpoint(&self, step: u64) -> Option<Checkpoint> {
+        self.checkpoints().take_while(|x| x.step <= step).last()
+    }
+
+    fn record_checkpoint(&mut self) {
+        let checkpoint = Checkpoint {
+            step: self.steps,
+            hash: self.hash(),
+        };
+        let Some(mut checkpoints) = self.checkpoints.take() else {
+            return;
+        };
+        if checkpoints.recorded.back().map(|x| x.step) != Some(checkpoint.step) {
+            if checkpoints.recorded.len() == checkpoints.keep {
+                checkpoints.recorded.pop_front();
+            }
+            if checkpoints.keep > 0 {
+                checkpoints.recorded.push_back(checkpoint);
+            }
+        }
+        let keep = checkpoints.keep;
+        if let Some(snapshots) = &mut checkpoints.snapshots {
+            if snapshots.back().map(|x| x.steps) != Some(checkpoint.step) {
+                if snapshots.len() == keep {
+                    snapshots.pop_front();
+                }
+                if keep > 0 {
+                    // taken while self.checkpoints is None, so snapshots don't nest
+                    snapshots.push_back(self.clone());
+                }
+            }
+        }
+        self.checkpoints = Some(checkpoints);
+    }
+
+    /// Starts counting the steps executed in each [`WorkCategory`], resetting any prior counts.
+    #[cfg(feature = "work-accounting")]
+    pub fn start_work_accounting(&mut self) {
+        self.work = Some(Default::default());
+    }
+
+    /// Summarizes the work counted since accounting started, if it has.
+    #[cfg(feature = "work-accounting")]
+    pub fn work_report(&self) -> Option<WorkReport> {
+        let work = self.work?;
+        let mut report = WorkReport::default();
+        for (category, steps) in WorkCategory::ALL.into_iter().zip(work) {
+            let cost = steps * category.weight();
+            report.steps += steps;
+            report.weighted_cost += cost;
+            report.per_category.push((format!("{category:?}"), cost));
+        }
+        Some(report)
+    }
+
+    /// Starts recording a [`StepJournal`] entry for each subsequent step.
+    #[cfg(feature = "step-journal")]
+    pub fn start_journal(&mut self) {
+        self.journal = Some(StepJournal {
+            start: self.steps + 1,
+            digests: vec![],
+        });
+    }
+
+    #[cfg(feature = "step-journal")]
+    pub fn journal_mut(&mut self) -> Option<&mut StepJournal> {
+        self.journal.as_mut()
+    }
+
+    /// Stops recording, returning the journal's unflushed contents.
+    #[cfg(feature = "step-journal")]
+    pub fn stop_journal(&mut self) -> Option<StepJournal> {
+        self.journal.take()
+    }
+
     pub fn set_context(&mut self, context: u64) {
         self.context = context;
     }
 
     pub fn add_inbox_msg(&mut self, identifier: InboxIdentifier, index: u64, data: Vec<u8>) {
+        log::debug!(
+            "added {identifier:?} inbox message {index} ({} bytes)",
+            data.len()
+        );
         self.inbox_contents.insert((identifier, index), data);
         if index >= self.first_too_far && identifier == InboxIdentifier::Sequencer {
             self.first_too_far = index + 1
         }
     }
 
+    /// Identifies a prepared machine by its binary, inbox contents, and global state,
+    /// for use as a cache key. Unlike [`Machine::hash`], this ignores execution state,
+    /// but should be taken before stepping since execution advances the global state.
+    pub fn identity_digest(&self) -> Bytes32 {
+        let mut inbox: Vec<_> = self.inbox_contents.iter().collect();
+        inbox.sort_by_key(|((kind, index), _)| (*kind as u8, *index));
+
+        let mut h = Keccak256::new();
+        h.update(b"Machine identity:");
+        h.update(self.initial_hash);
+        h.update(self.global_state.hash());
+        for ((kind, index), data) in inbox {
+            h.update([*kind as u8]);
+            h.update(index.to_be_bytes());
+            h.update(crypto::keccak(data));
+        }
+        h.finalize().into()
+    }
+
     pub fn get_module_names(&self, module: usize) -> Option<&NameCustomSection> {
         self.modules.get(module).map(|m| &*m.names)
     }
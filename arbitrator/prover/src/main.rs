@@ -7,15 +7,18 @@ use arbutil::{format, Bytes32, Color, DebugColor, PreimageType};
 use eyre::{eyre, Context, Result};
 use fnv::{FnvHashMap as HashMap, FnvHashSet as HashSet};
 use prover::{
-    machine::{GlobalState, InboxIdentifier, Machine, MachineStatus, PreimageResolver, ProofInfo},
-    utils::{file_bytes, hash_preimage, CBytes},
+    machine::{
+        GlobalState, InboxIdentifier, Machine, MachineStatus, MerkleizePolicy, PreimageResolver,
+        ProofInfo,
+    },
+    utils::{file_bytes, preimages_from_dir, read_preimages, CBytes},
     wavm::Opcode,
 };
+use std::io::BufWriter;
 use std::sync::Arc;
-use std::{convert::TryInto, io::BufWriter};
 use std::{
     fs::File,
-    io::{BufReader, ErrorKind, Read, Write},
+    io::{BufReader, Read, Write},
     path::{Path, PathBuf},
 };
 use structopt::StructOpt;
@@ -36,6 +39,9 @@ struct Opts {
     inbox_add_stub_headers: bool,
     #[structopt(long)]
     always_merkleize: bool,
+    /// which modules' memory merkles to cache: all, none, or comma-separated module indices
+    #[structopt(long)]
+    merkleize_policy: Option<MerkleizePolicy>,
     #[structopt(long)]
     debug_funcs: bool,
     #[structopt(long)]
@@ -67,15 +73,18 @@ struct Opts {
     #[structopt(long, default_value = "0")]
     position_within_message: u64,
     #[structopt(long)]
-    last_block_hash: Option<String>,
+    last_block_hash: Option<Bytes32>,
     #[structopt(long)]
-    last_send_root: Option<String>,
+    last_send_root: Option<Bytes32>,
     #[structopt(long)]
     inbox: Vec<PathBuf>,
     #[structopt(long)]
     delayed_inbox: Vec<PathBuf>,
     #[structopt(long)]
     preimages: Option<PathBuf>,
+    /// directories of keccak preimage files, each named by its hash
+    #[structopt(long)]
+    extra_preimages_dir: Vec<PathBuf>,
     #[structopt(long)]
     stylus_modules: Vec<PathBuf>,
     /// Require that the machine end in the Finished state
@@ -96,21 +105,6 @@ fn file_with_stub_header(path: &Path, headerlength: usize) -> Result<Vec<u8>> {
     Ok(msg)
 }
 
-fn decode_hex_arg(arg: &Option<String>, name: &str) -> Result<Bytes32> {
-    if let Some(arg) = arg {
-        let mut arg = arg.as_str();
-        if arg.starts_with("0x") {
-            arg = &arg[2..];
-        }
-        let mut bytes32 = Bytes32::default();
-        hex::decode_to_slice(arg, &mut bytes32.0)
-            .wrap_err_with(|| format!("failed to parse {} contents", name))?;
-        Ok(bytes32)
-    } else {
-        Ok(Bytes32::default())
-    }
-}
-
 #[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
 struct SimpleProfile {
     count: u64,
@@ -123,6 +117,8 @@ const DELAYED_HEADER_LEN: usize = 112; // also in test-case's host-io.rs & contr
 
 #[cfg(feature = "native")]
 fn main() -> Result<()> {
+    // the library only emits log records, leaving it to binaries like this one to show them
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
     let opts = Opts::from_args();
 
     if opts.print_wasmmoduleroot {
@@ -168,35 +164,24 @@ fn main() -> Result<()> {
 
     let mut preimages: HashMap<PreimageType, HashMap<Bytes32, CBytes>> = HashMap::default();
     if let Some(path) = opts.preimages {
-        let mut file = BufReader::new(File::open(path)?);
-        loop {
-            let mut ty_buf = [0u8; 1];
-            match file.read_exact(&mut ty_buf) {
-                Ok(()) => {}
-                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
-                Err(e) => return Err(e.into()),
-            }
-            let preimage_ty: PreimageType = ty_buf[0].try_into()?;
-
-            let mut size_buf = [0u8; 8];
-            file.read_exact(&mut size_buf)?;
-            let size = u64::from_le_bytes(size_buf) as usize;
-            let mut buf = vec![0u8; size];
-            file.read_exact(&mut buf)?;
-
-            let hash = hash_preimage(&buf, preimage_ty)?;
-            preimages
-                .entry(preimage_ty)
-                .or_default()
-                .insert(hash.into(), buf.as_slice().into());
-        }
+        let file = File::open(&path).wrap_err_with(|| format!("failed to open {path:?}"))?;
+        preimages = read_preimages(BufReader::new(file))
+            .wrap_err_with(|| format!("failed to read preimages from {path:?}"))?;
+    }
+    for dir in &opts.extra_preimages_dir {
+        let loaded = preimages_from_dir(dir, PreimageType::Keccak256, true)?;
+        println!("read {} preimages from {:?}", loaded.len(), dir);
+        preimages
+            .entry(PreimageType::Keccak256)
+            .or_default()
+            .extend(loaded);
     }
     let preimage_resolver =
         Arc::new(move |_, ty, hash| preimages.get(&ty).and_then(|m| m.get(&hash)).cloned())
             as PreimageResolver;
 
-    let last_block_hash = decode_hex_arg(&opts.last_block_hash, "--last-block-hash")?;
-    let last_send_root = decode_hex_arg(&opts.last_send_root, "--last-send-root")?;
+    let last_block_hash = opts.last_block_hash.unwrap_or_default();
+    let last_send_root = opts.last_send_root.unwrap_or_default();
 
     let global_state = GlobalState {
         u64_vals: [opts.inbox_position, opts.position_within_message],
@@ -223,6 +208,9 @@ fn main() -> Result<()> {
         mach.add_program(&wasm, codehash, 1, true)
             .wrap_err_with(err)?;
     }
+    if let Some(policy) = opts.merkleize_policy {
+        mach.set_merkleize_policy(policy);
+    }
 
     if opts.print_modules {
         mach.print_modules();
@@ -420,6 +408,11 @@ fn main() -> Result<()> {
     println!("End machine status: {:?}", mach.get_status());
     println!("End machine hash: {}", mach.hash());
     println!("End machine stack: {:?}", mach.get_data_stack());
+    let preimage_stats = mach.stats().preimage;
+    println!(
+        "Preimages resolved: {} ({} bytes total, largest {} bytes)",
+        preimage_stats.resolutions, preimage_stats.bytes, preimage_stats.max_size,
+    );
     println!("End machine backtrace:");
     mach.print_backtrace(false);
 
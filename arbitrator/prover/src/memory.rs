@@ -2,7 +2,8 @@
 // For license information, see https://github.com/nitro/blob/master/LICENSE
 
 use crate::{
-    merkle::{Merkle, MerkleType},
+    merkle::{self, Merkle, MerkleType},
+    utils,
     value::{ArbValueType, Value},
 };
 use arbutil::Bytes32;
@@ -59,6 +60,31 @@ fn hash_leaf(bytes: [u8; Memory::LEAF_SIZE]) -> Bytes32 {
     h.finalize().into()
 }
 
+/// Hashes the raw contents of a memory leaf as it's committed to in the memory merkle,
+/// which is `keccak("Memory leaf:" ++ data)`. Memory past the end of a module's allocation
+/// reads as zeros, so the empty leaf is `memory_leaf_hash(&[0; 32])`.
+pub fn memory_leaf_hash(data: &[u8; Memory::LEAF_SIZE]) -> Bytes32 {
+    hash_leaf(*data)
+}
+
+/// Checks a proof from [`Machine::prove_memory_word`](crate::Machine::prove_memory_word)
+/// of the raw 32-byte leaf containing `address` against a memory merkle root.
+pub fn verify_memory_word(
+    root: Bytes32,
+    address: u64,
+    data: &[u8; Memory::LEAF_SIZE],
+    proof: &[u8],
+) -> bool {
+    let Ok(idx) = usize::try_from(address / Memory::LEAF_SIZE as u64) else {
+        return false;
+    };
+    if proof.first().map(|&x| usize::from(x)) != Some(Memory::MEMORY_LAYERS - 1) {
+        return false;
+    }
+    let leaf = memory_leaf_hash(data);
+    merkle::root_from_proof(MerkleType::Memory, leaf, idx, proof) == Some(root)
+}
+
 fn round_up_to_power_of_two(mut input: usize) -> usize {
     if input == 0 {
         return 1;
@@ -105,19 +131,21 @@ impl Memory {
         // Round the size up to 8 byte long leaves, then round up to the next power of two number of leaves
         let leaves = round_up_to_power_of_two(div_round_up(self.buffer.len(), Self::LEAF_SIZE));
 
-        #[cfg(feature = "rayon")]
-        let leaf_hashes = self.buffer.par_chunks(Self::LEAF_SIZE);
-
-        #[cfg(not(feature = "rayon"))]
-        let leaf_hashes = self.buffer.chunks(Self::LEAF_SIZE);
-
-        let mut leaf_hashes: Vec<Bytes32> = leaf_hashes
-            .map(|leaf| {
-                let mut full_leaf = [0u8; 32];
-                full_leaf[..leaf.len()].copy_from_slice(leaf);
-                hash_leaf(full_leaf)
-            })
-            .collect();
+        let mut leaf_hashes: Vec<Bytes32> = utils::with_hash_pool(|| {
+            #[cfg(feature = "rayon")]
+            let leaf_hashes = self.buffer.par_chunks(Self::LEAF_SIZE);
+
+            #[cfg(not(feature = "rayon"))]
+            let leaf_hashes = self.buffer.chunks(Self::LEAF_SIZE);
+
+            leaf_hashes
+                .map(|leaf| {
+                    let mut full_leaf = [0u8; 32];
+                    full_leaf[..leaf.len()].copy_from_slice(leaf);
+                    hash_leaf(full_leaf)
+                })
+                .collect()
+        });
         if leaf_hashes.len() < leaves {
             let empty_hash = hash_leaf([0u8; 32]);
             leaf_hashes.resize(leaves, empty_hash);
@@ -304,6 +332,25 @@ impl Memory {
         Ok(())
     }
 
+    /// Replaces the entire contents, which must be a whole number of pages within `max_size`.
+    pub fn replace(&mut self, data: Vec<u8>) -> Result<()> {
+        let size = data.len() as u64;
+        if size % Self::PAGE_SIZE != 0 {
+            bail!("memory image of {size} bytes isn't a whole number of pages");
+        }
+        if size / Self::PAGE_SIZE > self.max_size {
+            let max = self.max_size;
+            bail!("memory image of {size} bytes exceeds the maximum of {max} pages");
+        }
+        let had_merkle_tree = self.merkle.is_some();
+        self.merkle = None;
+        self.buffer = data;
+        if had_merkle_tree {
+            self.cache_merkle_tree();
+        }
+        Ok(())
+    }
+
     pub fn cache_merkle_tree(&mut self) {
         self.merkle = Some(self.merkelize().into_owned());
     }
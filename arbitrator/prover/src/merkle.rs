@@ -1,11 +1,21 @@
 // Copyright 2021-2023, Offchain Labs, Inc.
 // For license information, see https://github.com/nitro/blob/master/LICENSE
 
+use crate::utils;
 use arbutil::Bytes32;
 use digest::Digest;
-use serde::{Deserialize, Serialize};
+use serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer};
 use sha3::Keccak256;
-use std::convert::TryFrom;
+use std::{
+    convert::TryFrom,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
 #[cfg(feature = "rayon")]
 use rayon::prelude::*;
@@ -31,7 +41,7 @@ impl Default for MerkleType {
 impl MerkleType {
     pub fn get_prefix(self) -> &'static str {
         match self {
-            MerkleType::Empty => panic!("Attempted to get prefix of empty merkle type"),
+            MerkleType::Empty => "Empty merkle tree:",
             MerkleType::Value => "Value merkle tree:",
             MerkleType::Function => "Function merkle tree:",
             MerkleType::Instruction => "Instruction merkle tree:",
@@ -43,12 +53,280 @@ impl MerkleType {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MerkleError {
+    /// A leaf index was past the end of the tree.
+    IndexOutOfBounds { idx: usize, len: usize },
+    /// A tree was deeper than [`MAX_DEPTH`], so its capacity can't be represented.
+    DepthTooLarge { depth: usize, max: usize },
+    /// A deserialized tree's layers don't describe a valid merkle.
+    Malformed(&'static str),
+    /// A serialized tree was written in a format version this build can't read.
+    UnsupportedVersion(u8),
+}
+
+impl fmt::Display for MerkleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::IndexOutOfBounds { idx, len } => {
+                write!(f, "index {idx} out of bounds for merkle with {len} leaves")
+            }
+            Self::DepthTooLarge { depth, max } => {
+                write!(f, "merkle depth {depth} exceeds the maximum of {max}")
+            }
+            Self::Malformed(reason) => write!(f, "malformed merkle: {reason}"),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "unsupported merkle format version {version}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for MerkleError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProveError {
+    /// The tree has no leaves, so there's nothing to prove against.
+    EmptyTree,
+    /// The index is past the last leaf (for `prove`) or past the tree's capacity (for `prove_any`).
+    OutOfRange {
+        idx: usize,
+        len: usize,
+        capacity: usize,
+    },
+}
+
+impl fmt::Display for ProveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::EmptyTree => write!(f, "cannot prove against an empty merkle"),
+            Self::OutOfRange { idx, len, capacity } => write!(
+                f,
+                "index {idx} out of range for merkle with {len} leaves and capacity {capacity}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ProveError {}
+
+/// Threshold in nanoseconds above which merkle operations are logged, or `u64::MAX` when disabled.
+static SLOW_OP_THRESHOLD: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Logs any merkle construction taking longer than the threshold, or disables logging when `None`.
+/// Records are logged as warnings through the `log` crate.
+pub fn set_slow_op_threshold(threshold: Option<Duration>) {
+    let nanos = match threshold {
+        Some(threshold) => u64::try_from(threshold.as_nanos()).unwrap_or(u64::MAX - 1),
+        None => u64::MAX,
+    };
+    SLOW_OP_THRESHOLD.store(nanos, Ordering::Relaxed);
+}
+
+/// Starts timing an operation if slow-op logging is enabled.
+fn slow_op_timer() -> Option<Instant> {
+    (SLOW_OP_THRESHOLD.load(Ordering::Relaxed) != u64::MAX).then(Instant::now)
+}
+
+#[cold]
+fn report_slow_op(start: Instant, op: &str, ty: MerkleType, label: Option<&str>, leaves: usize) {
+    let elapsed = start.elapsed();
+    if elapsed.as_nanos() < SLOW_OP_THRESHOLD.load(Ordering::Relaxed).into() {
+        return;
+    }
+    let label = label.map(|x| format!(" ({x})")).unwrap_or_default();
+    log::warn!("slow merkle {op}: {ty:?} tree{label} with {leaves} leaves took {elapsed:?}");
+}
+
+/// A mutation or read of a [`Merkle`], as reported to its observer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MerkleEvent {
+    /// A leaf was set, whether or not its value changed.
+    Set { idx: usize },
+    /// The number of leaves changed.
+    Resize { old: usize, new: usize },
+    /// Interior nodes were rehashed after a leaf changed.
+    Rehash { nodes_recomputed: usize },
+    /// The root was read. Roots are kept up to date eagerly, so this is always cached.
+    Root { cached: bool },
+}
+
+/// Receives a [`Merkle`]'s events along with the tree. Called after each operation completes,
+/// so the tree it's passed already reflects the event.
+#[derive(Clone)]
+pub struct MerkleObserver(Arc<dyn Fn(&Merkle, MerkleEvent) + Send + Sync>);
+
+impl fmt::Debug for MerkleObserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "observer...")
+    }
+}
+
+/// A proof of several leaves at once from [`Merkle::prove_multi`], which includes each sibling
+/// only once, and none that can be computed from the other proven leaves.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultiProof {
+    /// The number of layers above the leaves.
+    pub depth: usize,
+    /// The proven leaves' indices, sorted and deduplicated.
+    pub indices: Vec<usize>,
+    /// The siblings needed to reach the root, layer by layer from the leaves up,
+    /// and left to right within each layer.
+    pub siblings: Vec<Bytes32>,
+}
+
+/// How many nodes [`Merkle::build_cooperative`] hashes between calls to its yield hook.
+pub const COOPERATIVE_YIELD_NODES: usize = 1 << 12;
+
+/// How many roots a subscription buffers before new ones are dropped.
+pub const ROOT_SUBSCRIPTION_BUFFER: usize = 1024;
+
+struct RootSubscriber {
+    id: u64,
+    sender: SyncSender<(u64, Bytes32)>,
+    last: Option<Bytes32>,
+}
+
+/// The receivers of a [`Merkle`]'s roots. Unlike the observer, these aren't shared by clones,
+/// since a clone goes on to produce its own roots.
+#[derive(Default)]
+struct RootSubscribers {
+    /// Whether there are any subscribers, so that reading a root needn't lock otherwise.
+    active: AtomicBool,
+    /// The next subscriber id, and the subscribers.
+    list: Mutex<(u64, Vec<RootSubscriber>)>,
+}
+
+impl Clone for RootSubscribers {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl fmt::Debug for RootSubscribers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "subscribers...")
+    }
+}
+
+/// The deepest tree supported, chosen so that a tree's capacity always fits in a `usize`.
+pub const MAX_DEPTH: usize = usize::BITS as usize;
+
+/// The version of a [`Merkle`]'s serialized layout, which is serialized ahead of the tree.
+/// Bump it whenever the layout changes, so that stale snapshots are rejected rather than misread.
+pub const FORMAT_VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(try_from = "RawMerkle")]
 pub struct Merkle {
     ty: MerkleType,
     layers: Vec<Vec<Bytes32>>,
     empty_layers: Vec<Bytes32>,
     min_depth: usize,
+    /// Names the tree in diagnostics. Not part of the tree's hash, equality, or serialization.
+    #[serde(skip)]
+    label: Option<&'static str>,
+    /// Notified of mutations. Shared by clones. Not part of the tree's equality or serialization.
+    #[serde(skip)]
+    observer: Option<MerkleObserver>,
+    /// Counts the mutations that changed the tree. Not part of the tree's equality or serialization.
+    #[serde(skip)]
+    version: u64,
+    #[serde(skip)]
+    subscribers: RootSubscribers,
+}
+
+impl PartialEq for Merkle {
+    fn eq(&self, other: &Self) -> bool {
+        self.ty == other.ty
+            && self.layers == other.layers
+            && self.empty_layers == other.empty_layers
+            && self.min_depth == other.min_depth
+    }
+}
+
+impl Eq for Merkle {}
+
+impl Serialize for Merkle {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Merkle", 5)?;
+        state.serialize_field("version", &FORMAT_VERSION)?;
+        state.serialize_field("ty", &self.ty)?;
+        state.serialize_field("layers", &self.layers)?;
+        state.serialize_field("empty_layers", &self.empty_layers)?;
+        state.serialize_field("min_depth", &self.min_depth)?;
+        state.end()
+    }
+}
+
+/// A [`Merkle`] as serialized, before its version, depth, and shape have been validated.
+#[derive(Deserialize)]
+struct RawMerkle {
+    version: u8,
+    ty: MerkleType,
+    layers: Vec<Vec<Bytes32>>,
+    empty_layers: Vec<Bytes32>,
+    min_depth: usize,
+}
+
+impl TryFrom<RawMerkle> for Merkle {
+    type Error = MerkleError;
+
+    fn try_from(raw: RawMerkle) -> Result<Self, Self::Error> {
+        if raw.version != FORMAT_VERSION {
+            return Err(MerkleError::UnsupportedVersion(raw.version));
+        }
+        let depth = raw.layers.len().max(raw.min_depth);
+        if depth > MAX_DEPTH {
+            return Err(MerkleError::DepthTooLarge {
+                depth,
+                max: MAX_DEPTH,
+            });
+        }
+        if raw.empty_layers.len() < raw.layers.len() {
+            return Err(MerkleError::Malformed("fewer empty hashes than layers"));
+        }
+        if raw.layers.last().map_or(false, |root| root.len() != 1) {
+            return Err(MerkleError::Malformed("top layer isn't a single root"));
+        }
+        for (lower, upper) in raw.layers.iter().zip(raw.layers.iter().skip(1)) {
+            if upper.len() != (lower.len() + 1) / 2 {
+                return Err(MerkleError::Malformed("layer sizes don't halve"));
+            }
+        }
+        match raw.layers.iter().position(|layer| layer.len() == 1) {
+            Some(root) => {
+                if raw.layers.len() != (root + 1).max(raw.min_depth) {
+                    return Err(MerkleError::Malformed(
+                        "layer count doesn't match minimum depth",
+                    ));
+                }
+                if raw.empty_layers.len() != raw.layers.len() {
+                    return Err(MerkleError::Malformed("more empty hashes than layers"));
+                }
+            }
+            None => {
+                // empty trees keep the empty hashes up to their minimum depth
+                let expected = raw.min_depth.max(1);
+                let default = raw.empty_layers.is_empty() && raw.min_depth == 0;
+                if raw.empty_layers.len() != expected && !default {
+                    return Err(MerkleError::Malformed(
+                        "empty hashes don't match minimum depth",
+                    ));
+                }
+            }
+        }
+        Ok(Merkle {
+            ty: raw.ty,
+            layers: raw.layers,
+            empty_layers: raw.empty_layers,
+            min_depth: raw.min_depth,
+            label: None,
+            observer: None,
+            version: 0,
+            subscribers: RootSubscribers::default(),
+        })
+    }
 }
 
 fn hash_node(ty: MerkleType, a: Bytes32, b: Bytes32) -> Bytes32 {
@@ -59,6 +337,102 @@ fn hash_node(ty: MerkleType, a: Bytes32, b: Bytes32) -> Bytes32 {
     h.finalize().into()
 }
 
+/// Folds a proof from [`Merkle::prove_any`] into the root it proves the leaf against,
+/// or `None` if the proof is malformed or too short to reach the leaf's index.
+pub(crate) fn root_from_proof(
+    ty: MerkleType,
+    leaf: Bytes32,
+    mut idx: usize,
+    proof: &[u8],
+) -> Option<Bytes32> {
+    let (&count, siblings) = proof.split_first()?;
+    if siblings.len() != 32 * usize::from(count) || idx >> count.min(63) != 0 {
+        return None;
+    }
+    let mut hash = leaf;
+    for sibling in siblings.chunks_exact(32) {
+        let sibling = Bytes32::try_from(sibling).unwrap();
+        hash = match idx % 2 {
+            0 => hash_node(ty, hash, sibling),
+            _ => hash_node(ty, sibling, hash),
+        };
+        idx >>= 1;
+    }
+    Some(hash)
+}
+
+/// Checks a proof from [`Merkle::prove`] or [`Merkle::prove_any`] that `leaf` is at `idx`
+/// in the tree with the given root and depth, which is the number of layers above its leaves.
+/// The depth comes from the verifier rather than the proof, since a shorter proof could
+/// otherwise pass off an interior node as a leaf.
+pub fn verify_proof(
+    ty: MerkleType,
+    depth: usize,
+    leaf: Bytes32,
+    idx: usize,
+    proof: &[u8],
+    root: Bytes32,
+) -> bool {
+    proof.first().map(|&count| usize::from(count)) == Some(depth)
+        && root_from_proof(ty, leaf, idx, proof) == Some(root)
+}
+
+/// Checks a [`MultiProof`] that each leaf is at its index in the tree with the given root and
+/// depth, which as with [`verify_proof`] comes from the verifier rather than the proof.
+/// Indices may be unsorted or repeated, so long as a repeated index has the same leaf.
+pub fn verify_multi(
+    ty: MerkleType,
+    depth: usize,
+    indices: &[usize],
+    leaves: &[Bytes32],
+    proof: &MultiProof,
+    root: Bytes32,
+) -> bool {
+    if proof.depth != depth || indices.len() != leaves.len() || indices.is_empty() {
+        return false;
+    }
+    let mut nodes: Vec<_> = indices
+        .iter()
+        .copied()
+        .zip(leaves.iter().copied())
+        .collect();
+    nodes.sort_by_key(|x| x.0);
+    if nodes
+        .windows(2)
+        .any(|x| x[0].0 == x[1].0 && x[0].1 != x[1].1)
+    {
+        return false;
+    }
+    nodes.dedup();
+    if nodes.iter().any(|x| x.0 >> proof.depth.min(63) != 0) {
+        return false;
+    }
+
+    let mut siblings = proof.siblings.iter().copied();
+    for _ in 0..proof.depth {
+        let mut next = Vec::with_capacity(nodes.len());
+        let mut i = 0;
+        while i < nodes.len() {
+            let (idx, hash) = nodes[i];
+            let pair = match (idx % 2, nodes.get(i + 1)) {
+                (0, Some(&(right, sibling))) if right == idx + 1 => {
+                    i += 1;
+                    Some((hash, sibling))
+                }
+                (0, _) => siblings.next().map(|x| (hash, x)),
+                _ => siblings.next().map(|x| (x, hash)),
+            };
+            let Some((left, right)) = pair else {
+                return false;
+            };
+            next.push((idx >> 1, hash_node(ty, left, right)));
+            i += 1;
+        }
+        nodes = next;
+    }
+    siblings.next().is_none() && nodes == [(0, root)]
+}
+
 impl Merkle {
     pub fn new(ty: MerkleType, hashes: Vec<Bytes32>) -> Merkle {
         Self::new_advanced(ty, hashes, Bytes32::default(), 0)
@@ -69,44 +443,243 @@ impl Merkle {
         hashes: Vec<Bytes32>,
         empty_hash: Bytes32,
         min_depth: usize,
+    ) -> Merkle {
+        match Self::try_new_advanced(ty, hashes, empty_hash, min_depth) {
+            Ok(merkle) => merkle,
+            Err(err) => panic!("{err}"),
+        }
+    }
+
+    /// Like [`Merkle::new_advanced`], but errors instead of panicking when `min_depth` is too large.
+    pub fn try_new_advanced(
+        ty: MerkleType,
+        hashes: Vec<Bytes32>,
+        empty_hash: Bytes32,
+        min_depth: usize,
+    ) -> Result<Merkle, MerkleError> {
+        if min_depth > MAX_DEPTH {
+            return Err(MerkleError::DepthTooLarge {
+                depth: min_depth,
+                max: MAX_DEPTH,
+            });
+        }
+        Ok(Self::build(ty, hashes, empty_hash, min_depth, None, None))
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "new_advanced",
+            level = "trace",
+            skip_all,
+            fields(ty = ?ty, leaves = hashes.len(), min_depth = min_depth, label = label)
+        )
+    )]
+    fn build(
+        ty: MerkleType,
+        hashes: Vec<Bytes32>,
+        empty_hash: Bytes32,
+        min_depth: usize,
+        label: Option<&'static str>,
+        mut yield_fn: Option<&mut dyn FnMut()>,
     ) -> Merkle {
         if hashes.is_empty() {
-            return Merkle::default();
+            // keep the shape so that the root matches that of a tree emptied by truncation
+            let mut empty_layers = vec![empty_hash];
+            while empty_layers.len() < min_depth {
+                let empty_layer = *empty_layers.last().unwrap();
+                empty_layers.push(hash_node(ty, empty_layer, empty_layer));
+            }
+            return Merkle {
+                ty,
+                empty_layers,
+                min_depth,
+                label,
+                ..Merkle::default()
+            };
         }
+        let timer = slow_op_timer();
+        let leaves = hashes.len();
+        // without a yield hook, each layer is hashed in one batch
+        let batch_len = match yield_fn {
+            Some(_) => 2 * COOPERATIVE_YIELD_NODES,
+            None => usize::MAX,
+        };
+        let mut yield_now = || {
+            if let Some(yield_fn) = yield_fn.as_mut() {
+                yield_fn();
+            }
+        };
         let mut layers = vec![hashes];
         let mut empty_layers = vec![empty_hash];
         while layers.last().unwrap().len() > 1 || layers.len() < min_depth {
             let empty_layer = *empty_layers.last().unwrap();
+            let layer = layers.last().unwrap();
+            let mut new_layer = Vec::with_capacity((layer.len() + 1) / 2);
+            let mut batches = layer.chunks(batch_len).peekable();
+            while let Some(batch) = batches.next() {
+                let hash = |chunk: &[Bytes32]| {
+                    hash_node(ty, chunk[0], chunk.get(1).cloned().unwrap_or(empty_layer))
+                };
+                utils::with_hash_pool(|| {
+                    #[cfg(feature = "rayon")]
+                    new_layer.par_extend(batch.par_chunks(2).map(hash));
 
-            #[cfg(feature = "rayon")]
-            let new_layer = layers.last().unwrap().par_chunks(2);
-
-            #[cfg(not(feature = "rayon"))]
-            let new_layer = layers.last().unwrap().chunks(2);
-
-            let new_layer = new_layer
-                .map(|chunk| hash_node(ty, chunk[0], chunk.get(1).cloned().unwrap_or(empty_layer)))
-                .collect();
+                    #[cfg(not(feature = "rayon"))]
+                    new_layer.extend(batch.chunks(2).map(hash));
+                });
+                if batches.peek().is_some() {
+                    yield_now();
+                }
+            }
             empty_layers.push(hash_node(ty, empty_layer, empty_layer));
             layers.push(new_layer);
+            yield_now();
+        }
+        if let Some(start) = timer {
+            report_slow_op(start, "new_advanced", ty, label, leaves);
         }
         Merkle {
             ty,
             layers,
             empty_layers,
             min_depth,
+            label,
+            observer: None,
+            version: 0,
+            subscribers: RootSubscribers::default(),
+        }
+    }
+
+    /// Like [`Merkle::new_advanced`], but calls `yield_fn` after each layer and after every
+    /// [`COOPERATIVE_YIELD_NODES`] nodes within a layer, so that building a large tree can
+    /// share its thread with other work or report progress. The result is identical.
+    pub fn build_cooperative(
+        ty: MerkleType,
+        hashes: Vec<Bytes32>,
+        empty_hash: Bytes32,
+        min_depth: usize,
+        mut yield_fn: impl FnMut(),
+    ) -> Result<Merkle, MerkleError> {
+        if min_depth > MAX_DEPTH {
+            return Err(MerkleError::DepthTooLarge {
+                depth: min_depth,
+                max: MAX_DEPTH,
+            });
+        }
+        let yield_fn: &mut dyn FnMut() = &mut yield_fn;
+        let merkle = Self::build(ty, hashes, empty_hash, min_depth, None, Some(yield_fn));
+        Ok(merkle)
+    }
+
+    /// Registers a callback invoked with each [`MerkleEvent`], replacing any previous observer.
+    /// Reading the root from the callback reports a [`MerkleEvent::Root`] of its own.
+    pub fn set_observer(
+        &mut self,
+        observer: impl Fn(&Merkle, MerkleEvent) + Send + Sync + 'static,
+    ) {
+        self.observer = Some(MerkleObserver(Arc::new(observer)));
+    }
+
+    pub fn clear_observer(&mut self) {
+        self.observer = None;
+    }
+
+    fn notify(&self, event: MerkleEvent) {
+        if let Some(observer) = &self.observer {
+            (observer.0)(self, event);
+        }
+    }
+
+    /// Streams `(version, root)` each time [`Merkle::root`] returns a root different from the
+    /// last one sent to this receiver. At most [`ROOT_SUBSCRIPTION_BUFFER`] roots are buffered:
+    /// while the receiver lags, newer roots are dropped rather than blocking the tree, and
+    /// delivery resumes with the current root on the next read once there's room. Dropping the receiver
+    /// ends the subscription. Clones of the tree start without subscribers.
+    pub fn subscribe_roots(&self) -> Receiver<(u64, Bytes32)> {
+        let (sender, receiver) = mpsc::sync_channel(ROOT_SUBSCRIPTION_BUFFER);
+        let mut list = self.subscribers.list.lock().unwrap();
+        let (next_id, subscribers) = &mut *list;
+        let id = *next_id;
+        *next_id += 1;
+        subscribers.push(RootSubscriber {
+            id,
+            sender,
+            last: None,
+        });
+        self.subscribers.active.store(true, Ordering::Relaxed);
+        receiver
+    }
+
+    /// The number of mutations that have changed the tree since it was built or deserialized.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    fn publish_root(&self, root: Bytes32) {
+        if !self.subscribers.active.load(Ordering::Relaxed) {
+            return;
+        }
+        let senders: Vec<_> = {
+            let list = self.subscribers.list.lock().unwrap();
+            let subscribers = list.1.iter().filter(|x| x.last != Some(root));
+            subscribers.map(|x| (x.id, x.sender.clone())).collect()
+        };
+        if senders.is_empty() {
+            return;
+        }
+
+        // send outside the lock so that a receiver can't stall other readers
+        let mut sent = vec![];
+        let mut disconnected = vec![];
+        for (id, sender) in senders {
+            match sender.try_send((self.version, root)) {
+                Ok(()) => sent.push(id),
+                Err(TrySendError::Full(_)) => {}
+                Err(TrySendError::Disconnected(_)) => disconnected.push(id),
+            }
+        }
+
+        let mut list = self.subscribers.list.lock().unwrap();
+        let subscribers = &mut list.1;
+        subscribers.retain(|x| !disconnected.contains(&x.id));
+        for subscriber in subscribers.iter_mut().filter(|x| sent.contains(&x.id)) {
+            subscriber.last = Some(root);
         }
+        let active = !subscribers.is_empty();
+        self.subscribers.active.store(active, Ordering::Relaxed);
+    }
+
+    /// Names the tree in diagnostics such as slow-op logs and tracing spans.
+    pub fn set_label(&mut self, label: &'static str) {
+        self.label = Some(label);
+    }
+
+    pub fn with_label(mut self, label: &'static str) -> Self {
+        self.set_label(label);
+        self
+    }
+
+    pub fn label(&self) -> Option<&'static str> {
+        self.label
     }
 
     pub fn root(&self) -> Bytes32 {
-        if let Some(layer) = self.layers.last() {
+        let root = if let Some(layer) = self.layers.last() {
             assert_eq!(layer.len(), 1);
             layer[0]
+        } else if let Some(depth) = self.min_depth.checked_sub(1) {
+            // a tree emptied by truncation still has its depth
+            self.empty_layers.get(depth).copied().unwrap_or_default()
         } else {
             Bytes32::default()
-        }
+        };
+        self.notify(MerkleEvent::Root { cached: true });
+        self.publish_root(root);
+        root
     }
 
+    /// A view of the leaves with content, excluding the empty ones that pad out the capacity.
     pub fn leaves(&self) -> &[Bytes32] {
         if self.layers.is_empty() {
             &[]
@@ -115,17 +688,69 @@ impl Merkle {
         }
     }
 
-    #[must_use]
-    pub fn prove(&self, idx: usize) -> Option<Vec<u8>> {
-        if idx >= self.leaves().len() {
-            return None;
+    /// The number of leaves with content.
+    pub fn len(&self) -> usize {
+        self.leaves().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves().is_empty()
+    }
+
+    /// The leaf at the given index, or `None` if it's past the last leaf.
+    pub fn get_leaf(&self, idx: usize) -> Option<Bytes32> {
+        self.leaves().get(idx).copied()
+    }
+
+    /// The number of layers above the leaves, and so the number of siblings in a proof.
+    pub fn depth(&self) -> usize {
+        self.layers.len().saturating_sub(1)
+    }
+
+    /// The number of leaves the tree can hold without growing another layer.
+    pub fn capacity(&self) -> usize {
+        match self.layers.len() {
+            0 => 0,
+            layers => 1usize.checked_shl(layers as u32 - 1).unwrap_or(usize::MAX),
+        }
+    }
+
+    /// Creates a merkle proof for a leaf with content.
+    pub fn prove(&self, idx: usize) -> Result<Vec<u8>, ProveError> {
+        let len = self.leaves().len();
+        if len == 0 {
+            return Err(ProveError::EmptyTree);
+        }
+        if idx >= len {
+            let capacity = self.capacity();
+            return Err(ProveError::OutOfRange { idx, len, capacity });
         }
-        Some(self.prove_any(idx))
+        self.prove_any(idx)
+    }
+
+    /// Creates a merkle proof for a leaf with content, returning the leaf's hash alongside it.
+    pub fn prove_with_value(&self, idx: usize) -> Result<(Bytes32, Vec<u8>), ProveError> {
+        let proof = self.prove(idx)?;
+        Ok((self.leaves()[idx], proof))
     }
 
-    /// creates a merkle proof regardless of if the leaf has content
+    /// Like [`Merkle::prove`], but doesn't distinguish why a proof couldn't be made.
     #[must_use]
-    pub fn prove_any(&self, mut idx: usize) -> Vec<u8> {
+    pub fn prove_opt(&self, idx: usize) -> Option<Vec<u8>> {
+        self.prove(idx).ok()
+    }
+
+    /// Creates a merkle proof regardless of if the leaf has content,
+    /// so long as it's within the tree's capacity.
+    pub fn prove_any(&self, mut idx: usize) -> Result<Vec<u8>, ProveError> {
+        let len = self.leaves().len();
+        let capacity = self.capacity();
+        if len == 0 {
+            return Err(ProveError::EmptyTree);
+        }
+        if idx >= capacity {
+            return Err(ProveError::OutOfRange { idx, len, capacity });
+        }
         let mut proof = vec![u8::try_from(self.layers.len() - 1).unwrap()];
         for (layer_i, layer) in self.layers.iter().enumerate() {
             if layer_i == self.layers.len() - 1 {
@@ -140,31 +765,142 @@ impl Merkle {
             );
             idx >>= 1;
         }
-        proof
+        Ok(proof)
+    }
+
+    /// Proves several leaves within the tree's capacity at once, sharing siblings between them.
+    /// Indices may be unsorted or repeated.
+    pub fn prove_multi(&self, indices: &[usize]) -> Result<MultiProof, ProveError> {
+        let len = self.leaves().len();
+        let capacity = self.capacity();
+        if len == 0 {
+            return Err(ProveError::EmptyTree);
+        }
+        if let Some(&idx) = indices.iter().find(|&&idx| idx >= capacity) {
+            return Err(ProveError::OutOfRange { idx, len, capacity });
+        }
+        let mut indices = indices.to_vec();
+        indices.sort_unstable();
+        indices.dedup();
+
+        let mut siblings = vec![];
+        let mut known = indices.clone();
+        for (layer_i, layer) in self.layers.iter().enumerate().take(self.layers.len() - 1) {
+            let mut next = Vec::with_capacity(known.len());
+            let mut i = 0;
+            while i < known.len() {
+                let idx = known[i];
+                if idx % 2 == 0 && known.get(i + 1) == Some(&(idx + 1)) {
+                    i += 1;
+                } else {
+                    let sibling = layer.get(idx ^ 1).cloned();
+                    siblings.push(sibling.unwrap_or(self.empty_layers[layer_i]));
+                }
+                next.push(idx >> 1);
+                i += 1;
+            }
+            known = next;
+        }
+        Ok(MultiProof {
+            depth: self.layers.len() - 1,
+            indices,
+            siblings,
+        })
     }
 
     /// Adds a new leaf to the merkle
     /// Currently O(n) in the number of leaves (could be log(n))
     pub fn push_leaf(&mut self, leaf: Bytes32) {
-        let mut leaves = self.layers.swap_remove(0);
+        let old = self.leaves().len();
+        let mut leaves = match self.layers.is_empty() {
+            true => vec![],
+            false => self.layers.swap_remove(0),
+        };
         leaves.push(leaf);
-        let empty = self.empty_layers[0];
-        *self = Self::new_advanced(self.ty, leaves, empty, self.min_depth);
+        self.rebuild(old, leaves);
     }
 
-    /// Removes the rightmost leaf from the merkle
-    /// Currently O(n) in the number of leaves (could be log(n))
+    /// Removes the rightmost leaf from the merkle, doing nothing if it's empty.
     pub fn pop_leaf(&mut self) {
-        let mut leaves = self.layers.swap_remove(0);
-        leaves.pop();
-        let empty = self.empty_layers[0];
-        *self = Self::new_advanced(self.ty, leaves, empty, self.min_depth);
+        self.truncate(self.leaves().len().saturating_sub(1));
     }
 
-    pub fn set(&mut self, mut idx: usize, hash: Bytes32) {
-        if self.layers[0][idx] == hash {
+    /// Drops the leaves from `new_len` onward, rehashing only the path to the new last leaf.
+    /// An emptied tree keeps its type and minimum depth, so its root is that of an empty subtree.
+    pub fn truncate(&mut self, new_len: usize) {
+        let old = self.leaves().len();
+        if new_len >= old {
+            return;
+        }
+        self.version += 1;
+        if new_len == 0 {
+            self.layers.clear();
+            self.empty_layers.truncate(self.min_depth.max(1));
+            self.notify(MerkleEvent::Resize { old, new: 0 });
             return;
         }
+
+        let mut depth = 1;
+        let mut len = new_len;
+        while len > 1 || depth < self.min_depth {
+            len = (len + 1) / 2;
+            depth += 1;
+        }
+        self.layers.truncate(depth);
+        self.empty_layers.truncate(depth);
+        self.layers[0].truncate(new_len);
+        for layer_i in 1..depth {
+            let (lower, upper) = self.layers.split_at_mut(layer_i);
+            let lower = &lower[layer_i - 1];
+            let upper = &mut upper[0];
+            upper.truncate((lower.len() + 1) / 2);
+
+            let idx = upper.len() - 1;
+            let left = lower[2 * idx];
+            let right = lower.get(2 * idx + 1).cloned();
+            let right = right.unwrap_or(self.empty_layers[layer_i - 1]);
+            upper[idx] = hash_node(self.ty, left, right);
+        }
+        self.notify(MerkleEvent::Resize { old, new: new_len });
+        self.notify(MerkleEvent::Rehash {
+            nodes_recomputed: depth - 1,
+        });
+    }
+
+    /// Reconstructs the tree from a new set of leaves, keeping its type, shape, label, observer,
+    /// and subscribers.
+    fn rebuild(&mut self, old: usize, leaves: Vec<Bytes32>) {
+        let new = leaves.len();
+        let empty = self.empty_layers.first().copied().unwrap_or_default();
+        let observer = self.observer.take();
+        let subscribers = std::mem::take(&mut self.subscribers);
+        let version = self.version + 1;
+        *self = Self::build(self.ty, leaves, empty, self.min_depth, self.label, None);
+        self.observer = observer;
+        self.subscribers = subscribers;
+        self.version = version;
+        self.notify(MerkleEvent::Resize { old, new });
+    }
+
+    /// Sets a leaf, panicking if it's out of bounds.
+    /// The machine only sets leaves it has already bounds-checked.
+    pub fn set(&mut self, idx: usize, hash: Bytes32) {
+        if let Err(err) = self.try_set(idx, hash) {
+            utils::ProverPanic::IndexOutOfBounds(err.to_string()).raise();
+        }
+    }
+
+    pub fn try_set(&mut self, mut idx: usize, hash: Bytes32) -> Result<(), MerkleError> {
+        let len = self.leaves().len();
+        if idx >= len {
+            return Err(MerkleError::IndexOutOfBounds { idx, len });
+        }
+        if self.layers[0][idx] == hash {
+            self.notify(MerkleEvent::Set { idx });
+            return Ok(());
+        }
+        let leaf = idx;
+        self.version += 1;
         let mut next_hash = hash;
         let empty_layers = &self.empty_layers;
         let layers_len = self.layers.len();
@@ -185,5 +921,9 @@ impl Merkle {
             }
             idx >>= 1;
         }
+        let nodes_recomputed = layers_len - 1;
+        self.notify(MerkleEvent::Rehash { nodes_recomputed });
+        self.notify(MerkleEvent::Set { idx: leaf });
+        Ok(())
     }
 }
@@ -0,0 +1,26 @@
+// Copyright 2024, Offchain Labs, Inc.
+// For license information, see https://github.com/OffchainLabs/nitro/blob/master/LICENSE
+
+//! The stable surface of the prover for downstream crates.
+//!
+//! Items here won't be removed or renamed without a breaking version bump,
+//! regardless of how the modules they're defined in are reorganized.
+//!
+//! ```
+//! use prover::prelude::*;
+//!
+//! let merkle = Merkle::new(MerkleType::Value, vec![Bytes32::default(); 3]);
+//! assert!(merkle.prove(2).is_ok());
+//! assert!(matches!(merkle.prove(3), Err(ProveError::OutOfRange { .. })));
+//!
+//! let resolver: PreimageResolver = std::sync::Arc::new(|_, _: PreimageType, _| None);
+//! assert!(resolver(0, PreimageType::Keccak256, Bytes32::default()).is_none());
+//! let _ = (MachineStatus::Running, GlobalState::default());
+//! ```
+
+pub use crate::{
+    machine::{GlobalState, Machine, MachineStatus, PreimageResolver},
+    merkle::{Merkle, MerkleError, MerkleType, ProveError},
+    utils::CBytes,
+};
+pub use arbutil::{Bytes32, PreimageType};
@@ -3,16 +3,120 @@
 
 #![cfg(test)]
 
-use crate::binary;
+use crate::{
+    binary,
+    machine::{
+        get_empty_preimage_resolver, CancelToken, GlobalState, InboxIdentifier, InboxPosition,
+        Machine, MachineStatus, MerkleizePolicy, PreimageResolver, PreimageResolverWrapper,
+        StepOutcome,
+    },
+    merkle::{self, Merkle, MerkleError, MerkleEvent, MerkleType, ProveError, MAX_DEPTH},
+    utils::{self, CBytes},
+};
+use arbutil::{crypto, Bytes32, PreimageType};
 use brotli::Dictionary;
 use eyre::Result;
-use std::path::Path;
+use fnv::FnvHashMap as HashMap;
+use std::{
+    fs,
+    path::Path,
+    sync::{Arc, Mutex, Once},
+    time::Duration,
+};
+
+/// Log records captured by the test logger.
+static LOGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+struct TestLogger;
+
+impl log::Log for TestLogger {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        LOGS.lock().unwrap().push(record.args().to_string());
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs a process-wide logger that captures every record into `LOGS`.
+fn capture_logs() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        log::set_logger(&TestLogger).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+    });
+}
+
+fn logged(pattern: &str) -> bool {
+    LOGS.lock()
+        .unwrap()
+        .iter()
+        .any(|line| line.contains(pattern))
+}
+
+/// Logs every merkle construction while running `f`. The threshold is process-wide,
+/// so tests that change it are serialized.
+fn logging_all_merkle_ops<R>(f: impl FnOnce() -> R) -> R {
+    static THRESHOLD: Mutex<()> = Mutex::new(());
+    let _guard = THRESHOLD
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    merkle::set_slow_op_threshold(Some(Duration::ZERO));
+    let result = f();
+    merkle::set_slow_op_threshold(None);
+    result
+}
 
 fn as_wasm(wat: &str) -> Vec<u8> {
     let wasm = wasmer::wat2wasm(wat.as_bytes());
     wasm.unwrap().to_vec()
 }
 
+fn machine_from_wat(wat: &str, name: &str, always_merkleize: bool) -> Result<Machine> {
+    let wasm = as_wasm(wat);
+    let bin = binary::parse(&wasm, Path::new(name))?;
+    Machine::from_binaries(
+        &[],
+        bin,
+        false,
+        always_merkleize,
+        false,
+        false,
+        false,
+        GlobalState::default(),
+        Default::default(),
+        get_empty_preimage_resolver(),
+        None,
+    )
+}
+
+/// Like [`machine_from_wat`], but the main module may import host functions.
+fn hostio_machine_from_wat(
+    wat: &str,
+    name: &str,
+    inbox_contents: HashMap<(InboxIdentifier, u64), Vec<u8>>,
+    preimage_resolver: PreimageResolver,
+) -> Result<Machine> {
+    let wasm = as_wasm(wat);
+    let bin = binary::parse(&wasm, Path::new(name))?;
+    Machine::from_binaries(
+        &[],
+        bin,
+        false,
+        false,
+        true,
+        false,
+        false,
+        GlobalState::default(),
+        inbox_contents,
+        preimage_resolver,
+        None,
+    )
+}
+
 #[test]
 pub fn reject_reexports() {
     let wasm = as_wasm(
@@ -69,3 +173,1497 @@ pub fn test_compress() -> Result<()> {
     }
     Ok(())
 }
+
+#[test]
+pub fn reject_mismatched_preimage() {
+    let preimage = b"preimage".to_vec();
+    let actual = Bytes32(crypto::keccak(&preimage));
+    let wrong = Bytes32([1; 32]);
+
+    let resolver =
+        Arc::new(move |_, _, hash| (hash == wrong).then(|| CBytes::from(preimage.as_slice())))
+            as PreimageResolver;
+
+    let mut wrapper = PreimageResolverWrapper::new(resolver);
+    wrapper.set_verify(true);
+    let err = wrapper.get(0, PreimageType::Keccak256, wrong).unwrap_err();
+    let err = err.to_string();
+    assert!(err.contains(&wrong.to_string()), "{err}");
+    assert!(err.contains(&actual.to_string()), "{err}");
+
+    wrapper.set_verify(false);
+    assert!(wrapper
+        .get(0, PreimageType::Keccak256, wrong)
+        .unwrap()
+        .is_some());
+}
+
+#[test]
+pub fn preimage_stats() {
+    let preimages: Vec<Vec<u8>> = vec![vec![1; 10], vec![2; 100], vec![3; 50]];
+    let hashes: Vec<Bytes32> = preimages.iter().map(|p| crypto::keccak(p).into()).collect();
+
+    let served = preimages.clone();
+    let known = hashes.clone();
+    let resolver = Arc::new(move |_, _, hash| {
+        let index = known.iter().position(|h| *h == hash)?;
+        Some(CBytes::from(served[index].as_slice()))
+    }) as PreimageResolver;
+
+    let mut wrapper = PreimageResolverWrapper::new(resolver);
+    for hash in &hashes {
+        // the second read of each hash is served from the cache
+        assert!(wrapper
+            .get(0, PreimageType::Keccak256, *hash)
+            .unwrap()
+            .is_some());
+        assert!(wrapper
+            .get(0, PreimageType::Keccak256, *hash)
+            .unwrap()
+            .is_some());
+    }
+    assert!(wrapper
+        .get(0, PreimageType::Keccak256, Bytes32::default())
+        .unwrap()
+        .is_none());
+
+    let stats = wrapper.stats();
+    assert_eq!(stats.resolutions, preimages.len() as u64);
+    assert_eq!(stats.bytes, 160);
+    assert_eq!(stats.max_size, 100);
+}
+
+#[test]
+pub fn preimages_from_dir() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("prover-preimages-{}", std::process::id()));
+    fs::create_dir_all(&dir)?;
+
+    let preimage = b"some preimage";
+    let hash = Bytes32(crypto::keccak(preimage));
+    fs::write(dir.join(hash.to_string()), preimage)?;
+
+    let loaded = utils::preimages_from_dir(&dir, PreimageType::Keccak256, true)?;
+    assert_eq!(loaded.len(), 1);
+    assert_eq!(loaded[&hash].as_slice(), preimage);
+
+    fs::write(dir.join("not-a-hash"), preimage)?;
+    let err = utils::preimages_from_dir(&dir, PreimageType::Keccak256, true).unwrap_err();
+    assert!(err.to_string().contains("not-a-hash"), "{err}");
+    fs::remove_file(dir.join("not-a-hash"))?;
+
+    fs::write(dir.join(Bytes32([1; 32]).to_string()), preimage)?;
+    let err = utils::preimages_from_dir(&dir, PreimageType::Keccak256, true).unwrap_err();
+    assert!(err.to_string().contains(&hash.to_string()), "{err}");
+    assert_eq!(
+        utils::preimages_from_dir(&dir, PreimageType::Keccak256, false)?.len(),
+        2
+    );
+
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+pub fn read_preimages() -> Result<()> {
+    let record = |ty: u8, data: &[u8]| {
+        let mut record = vec![ty];
+        record.extend((data.len() as u64).to_le_bytes());
+        record.extend(data);
+        record
+    };
+    let first = record(PreimageType::Keccak256.into(), b"first");
+    let second = record(PreimageType::Sha2_256.into(), b"second");
+    let file = [first.clone(), second.clone()].concat();
+
+    let preimages = utils::read_preimages(file.as_slice())?;
+    let keccak = Bytes32(crypto::keccak(b"first"));
+    assert_eq!(
+        preimages[&PreimageType::Keccak256][&keccak].as_slice(),
+        b"first"
+    );
+    assert_eq!(preimages[&PreimageType::Sha2_256].len(), 1);
+
+    let bad_type = [first.clone(), record(9, b"bad")].concat();
+    let err = utils::read_preimages(bad_type.as_slice()).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "preimage record 1 at byte offset {}: unknown preimage type 9",
+            first.len()
+        ),
+    );
+
+    let truncated = &file[..file.len() - 2];
+    let err = utils::read_preimages(truncated).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "preimage record 1 at byte offset {}: expected 6 bytes of data, got 4",
+            first.len()
+        ),
+    );
+
+    let err = utils::read_preimages(&file[..3]).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "preimage record 0 at byte offset 0: truncated length"
+    );
+    Ok(())
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+pub fn merkle_spans() {
+    use std::{
+        fmt,
+        sync::atomic::{AtomicU64, Ordering},
+    };
+    use tracing::{
+        field::{Field, Visit},
+        span, Event, Metadata, Subscriber,
+    };
+
+    type Span = (&'static str, Vec<(String, String)>);
+
+    #[derive(Default)]
+    struct Recorder {
+        spans: Arc<Mutex<Vec<Span>>>,
+        next_id: AtomicU64,
+    }
+
+    struct Fields<'a>(&'a mut Vec<(String, String)>);
+
+    impl Visit for Fields<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0.push((field.name().to_owned(), format!("{value:?}")));
+        }
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+        fn new_span(&self, span: &span::Attributes<'_>) -> span::Id {
+            let mut fields = vec![];
+            span.record(&mut Fields(&mut fields));
+            let name = span.metadata().name();
+            self.spans.lock().unwrap().push((name, fields));
+            span::Id::from_u64(self.next_id.fetch_add(1, Ordering::Relaxed) + 1)
+        }
+        fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+        fn event(&self, _: &Event<'_>) {}
+        fn enter(&self, _: &span::Id) {}
+        fn exit(&self, _: &span::Id) {}
+    }
+
+    let recorder = Recorder::default();
+    let spans = recorder.spans.clone();
+    tracing::subscriber::with_default(recorder, || {
+        let mut merkle = Merkle::new(MerkleType::Value, vec![Bytes32::default(); 5]);
+        merkle.push_leaf(Bytes32::default());
+    });
+
+    let spans = spans.lock().unwrap();
+    let field = |span: &Span, name: &str| {
+        let value = span.1.iter().find(|(field, _)| field == name);
+        value.map(|(_, value)| value.clone())
+    };
+    assert_eq!(spans.len(), 2);
+    for (span, leaves) in spans.iter().zip(["5", "6"]) {
+        assert_eq!(span.0, "new_advanced");
+        assert_eq!(field(span, "ty").as_deref(), Some("Value"));
+        assert_eq!(field(span, "leaves").as_deref(), Some(leaves));
+        assert_eq!(field(span, "min_depth").as_deref(), Some("0"));
+    }
+}
+
+#[test]
+pub fn slow_merkle_ops() {
+    capture_logs();
+    logging_all_merkle_ops(|| Merkle::new(MerkleType::Table, vec![Bytes32::default(); 77]));
+    assert!(logged(
+        "slow merkle new_advanced: Table tree with 77 leaves"
+    ));
+}
+
+#[test]
+pub fn merkle_labels() {
+    capture_logs();
+    let leaves = vec![Bytes32::from(1u64); 3];
+    let plain = Merkle::new(MerkleType::Module, leaves.clone());
+    let mut labeled = Merkle::new(MerkleType::Module, leaves).with_label("module 3 memory");
+    assert_eq!(labeled.label(), Some("module 3 memory"));
+    assert_eq!(labeled, plain);
+    assert_eq!(
+        bincode::serialize(&labeled).unwrap(),
+        bincode::serialize(&plain).unwrap()
+    );
+
+    logging_all_merkle_ops(|| labeled.push_leaf(Bytes32::default()));
+    assert_eq!(labeled.label(), Some("module 3 memory"));
+    assert!(logged("Module tree (module 3 memory) with 4 leaves"));
+}
+
+#[test]
+pub fn merkle_observer() {
+    let events = Arc::new(Mutex::new(vec![]));
+    let mut merkle = Merkle::new(MerkleType::Value, vec![Bytes32::default(); 4]);
+    let observed = events.clone();
+    merkle.set_observer(move |_, event| observed.lock().unwrap().push(event));
+
+    merkle.set(1, Bytes32::from(1u64));
+    merkle.set(1, Bytes32::from(1u64));
+    merkle.push_leaf(Bytes32::default());
+    merkle.root();
+    merkle.clear_observer();
+    merkle.pop_leaf();
+
+    use MerkleEvent::*;
+    assert_eq!(
+        *events.lock().unwrap(),
+        [
+            Rehash {
+                nodes_recomputed: 2
+            },
+            Set { idx: 1 },
+            Set { idx: 1 },
+            Resize { old: 4, new: 5 },
+            Root { cached: true },
+        ]
+    );
+}
+
+#[test]
+pub fn merkle_observer_sees_mutation() {
+    let roots = Arc::new(Mutex::new(vec![]));
+    let mut merkle = Merkle::new(MerkleType::Value, vec![Bytes32::default(); 4]);
+    let observed = roots.clone();
+    merkle.set_observer(move |merkle, event| {
+        if let MerkleEvent::Set { .. } = event {
+            observed.lock().unwrap().push(merkle.root());
+        }
+    });
+
+    let old = merkle.root();
+    merkle.set(1, Bytes32::from(1u64));
+    let new = merkle.root();
+    assert_ne!(old, new);
+    assert_eq!(*roots.lock().unwrap(), [new]);
+}
+
+#[test]
+pub fn merkle_root_subscription() {
+    let mut merkle = Merkle::new(MerkleType::Value, vec![Bytes32::default(); 4]);
+    let roots = merkle.subscribe_roots();
+
+    let mut observed = vec![merkle.root()];
+    merkle.set(1, Bytes32::from(1u64));
+    observed.push(merkle.root());
+    observed.push(merkle.root());
+    merkle.set(1, Bytes32::from(1u64));
+    merkle.set(2, Bytes32::from(2u64));
+    merkle.set(3, Bytes32::from(3u64));
+    observed.push(merkle.root());
+    merkle.push_leaf(Bytes32::from(4u64));
+    observed.push(merkle.root());
+    merkle.clone().set(0, Bytes32::from(5u64));
+
+    observed.dedup();
+    let received: Vec<_> = roots.try_iter().collect();
+    let versions: Vec<_> = received.iter().map(|x| x.0).collect();
+    assert_eq!(versions, [0, 1, 3, 4]);
+    let received: Vec<_> = received.into_iter().map(|x| x.1).collect();
+    assert_eq!(received, observed);
+
+    // a lagging receiver misses roots rather than blocking
+    let lagging = merkle.subscribe_roots();
+    for i in 0..merkle::ROOT_SUBSCRIPTION_BUFFER as u64 + 10 {
+        merkle.set(0, Bytes32::from(i + 100));
+        merkle.root();
+    }
+    assert_eq!(lagging.try_iter().count(), merkle::ROOT_SUBSCRIPTION_BUFFER);
+
+    // once there's room, the root that was dropped is delivered even though it hasn't changed
+    let root = merkle.root();
+    assert_eq!(lagging.try_recv().map(|x| x.1), Ok(root));
+    merkle.root();
+    assert!(lagging.try_recv().is_err());
+    drop(roots);
+    merkle.set(0, Bytes32::default());
+    merkle.root();
+}
+
+#[test]
+pub fn merkle_build_cooperative() {
+    let ty = MerkleType::Memory;
+    let empty = Bytes32::from(7u64);
+    for (count, min_depth, expected) in [(0, 3, 0), (8, 0, 3), (8, 6, 5), (1 << 14, 0, 15)] {
+        let leaves: Vec<_> = (0..count as u64).map(Bytes32::from).collect();
+        let mut yields = 0;
+        let built = Merkle::build_cooperative(ty, leaves.clone(), empty, min_depth, || yields += 1);
+        let merkle = Merkle::new_advanced(ty, leaves, empty, min_depth);
+        assert_eq!(built.unwrap(), merkle);
+        assert_eq!(yields, expected, "{count} leaves");
+    }
+    assert!(Merkle::build_cooperative(ty, vec![], empty, MAX_DEPTH + 1, || ()).is_err());
+}
+
+#[test]
+pub fn merkle_errors() {
+    let mut merkle = Merkle::new(MerkleType::Value, vec![Bytes32::default(); 3]);
+    let err = merkle.try_set(3, Bytes32::default()).unwrap_err();
+    assert_eq!(err, MerkleError::IndexOutOfBounds { idx: 3, len: 3 });
+    assert_eq!(
+        err.to_string(),
+        "index 3 out of bounds for merkle with 3 leaves"
+    );
+
+    let root = merkle.root();
+    merkle.try_set(2, Bytes32::from(2u64)).unwrap();
+    assert_ne!(merkle.root(), root);
+
+    let mut empty = Merkle::default();
+    let err = empty.try_set(0, Bytes32::default()).unwrap_err();
+    assert_eq!(err, MerkleError::IndexOutOfBounds { idx: 0, len: 0 });
+}
+
+#[test]
+pub fn default_merkle() {
+    let mut merkle = Merkle::default();
+    assert_eq!(merkle.root(), Bytes32::default());
+    assert!(merkle.try_set(0, Bytes32::default()).is_err());
+    assert_eq!(merkle.prove(0), Err(ProveError::EmptyTree));
+    assert_eq!(merkle.prove_any(0), Err(ProveError::EmptyTree));
+    merkle.pop_leaf();
+    assert!(merkle.leaves().is_empty());
+
+    let leaves = vec![
+        Bytes32::from(1u64),
+        Bytes32::from(2u64),
+        Bytes32::from(3u64),
+    ];
+    for leaf in &leaves {
+        merkle.push_leaf(*leaf);
+    }
+    let expected = Merkle::new(MerkleType::Empty, leaves);
+    assert_eq!(merkle, expected);
+    assert_ne!(merkle.root(), Bytes32::default());
+    assert!(merkle.prove(2).is_ok());
+
+    for _ in 0..3 {
+        merkle.pop_leaf();
+    }
+    assert_eq!(merkle.root(), Bytes32::default());
+}
+
+#[test]
+pub fn merkle_truncate() {
+    let ty = MerkleType::Memory;
+    let empty = Bytes32::from(9u64);
+    let leaves: Vec<_> = (1..=13u64).map(Bytes32::from).collect();
+    for min_depth in [0, 2, 6] {
+        let build =
+            |count: usize| Merkle::new_advanced(ty, leaves[..count].to_vec(), empty, min_depth);
+        for from in 1..=leaves.len() {
+            for to in 0..=from {
+                let mut merkle = build(from);
+                merkle.truncate(to);
+                assert_eq!(merkle, build(to), "{from} -> {to} at depth {min_depth}");
+            }
+        }
+
+        let mut merkle = build(5);
+        for count in (1..5).rev() {
+            merkle.pop_leaf();
+            assert_eq!(merkle, build(count));
+        }
+        merkle.pop_leaf();
+        assert!(merkle.leaves().is_empty());
+        assert_eq!(merkle, build(0));
+        let expected = match min_depth {
+            0 => Bytes32::default(),
+            _ => Merkle::new_advanced(ty, vec![empty], empty, min_depth).root(),
+        };
+        assert_eq!(merkle.root(), expected);
+        assert_eq!(build(0).root(), expected);
+        merkle.pop_leaf();
+
+        // the emptied tree keeps its type and empty hash when regrown
+        merkle.push_leaf(leaves[0]);
+        assert_eq!(merkle, build(1));
+    }
+}
+
+#[test]
+pub fn verify_merkle_proof() {
+    use MerkleType::*;
+    let types = [
+        Empty,
+        Value,
+        Function,
+        Instruction,
+        Memory,
+        Table,
+        TableElement,
+        Module,
+    ];
+    let leaves: Vec<_> = (1..=5u64).map(Bytes32::from).collect();
+    let empty = Bytes32::from(9u64);
+
+    for ty in types {
+        let merkle = Merkle::new(ty, leaves.clone());
+        let (root, depth) = (merkle.root(), merkle.depth());
+        for (idx, leaf) in leaves.iter().enumerate() {
+            let proof = merkle.prove(idx).unwrap();
+            let valid = merkle::verify_proof(ty, depth, *leaf, idx, &proof, root);
+            assert!(valid, "{ty:?} leaf {idx}");
+        }
+
+        // every other type's domain separation rejects the proof
+        let proof = merkle.prove(4).unwrap();
+        for other in types.into_iter().filter(|x| *x != ty) {
+            assert!(!merkle::verify_proof(
+                other, depth, leaves[4], 4, &proof, root
+            ));
+        }
+        assert!(!merkle::verify_proof(ty, depth, leaves[4], 3, &proof, root));
+        assert!(!merkle::verify_proof(ty, depth, leaves[3], 4, &proof, root));
+    }
+
+    // over-provisioned trees prove empty leaves past the end
+    let ty = Memory;
+    let merkle = Merkle::new_advanced(ty, leaves.clone(), empty, 6);
+    let (root, depth) = (merkle.root(), merkle.depth());
+    assert_eq!(depth, 5);
+    for idx in [4, 5, 31] {
+        let leaf = leaves.get(idx).copied().unwrap_or(empty);
+        let proof = merkle.prove_any(idx).unwrap();
+        assert_eq!(proof.len(), 1 + depth * 32);
+        assert!(merkle::verify_proof(ty, depth, leaf, idx, &proof, root));
+    }
+    let proof = merkle.prove_any(0).unwrap();
+    assert!(!merkle::verify_proof(ty, depth, empty, 32, &proof, root));
+
+    // an interior node passed off as a leaf with a shortened proof
+    let proof = merkle.prove(2).unwrap();
+    let sibling = Bytes32::try_from(&proof[1..33]).unwrap();
+    let interior = Merkle::new(ty, vec![leaves[2], sibling]).root();
+    let shortened = [&[4][..], &proof[33..]].concat();
+    assert_eq!(
+        merkle::root_from_proof(ty, interior, 1, &shortened),
+        Some(root)
+    );
+    assert!(!merkle::verify_proof(
+        ty, depth, interior, 1, &shortened, root
+    ));
+
+    let malformed = [
+        proof[..proof.len() - 1].to_vec(),
+        [&proof[..], &[0][..]].concat(),
+        [&proof[..], &[0; 32][..]].concat(),
+        [&[4][..], &proof[1..1 + 4 * 32]].concat(),
+        vec![],
+    ];
+    for proof in malformed {
+        assert!(!merkle::verify_proof(ty, depth, leaves[2], 2, &proof, root));
+    }
+}
+
+#[test]
+pub fn merkle_multiproof() {
+    let ty = MerkleType::Memory;
+    let empty = Bytes32::from(9u64);
+    let leaves: Vec<_> = (1..=37u64).map(Bytes32::from).collect();
+    let merkle = Merkle::new_advanced(ty, leaves.clone(), empty, 8);
+    let (root, depth) = (merkle.root(), merkle.depth());
+    let leaf = |idx: usize| leaves.get(idx).copied().unwrap_or(empty);
+
+    let sets = [
+        vec![5],
+        vec![5, 3, 5, 36, 0],
+        (8..24).collect(),
+        vec![127, 40, 36],
+        (0..128).collect(),
+    ];
+    for indices in sets {
+        let values: Vec<_> = indices.iter().map(|&x| leaf(x)).collect();
+        let multi = merkle.prove_multi(&indices).unwrap();
+        let valid = merkle::verify_multi(ty, depth, &indices, &values, &multi, root);
+        assert!(valid, "{indices:?}");
+
+        let mut singles = 0;
+        for &idx in &multi.indices {
+            let proof = merkle.prove_any(idx).unwrap();
+            assert!(merkle::verify_proof(
+                ty,
+                depth,
+                leaf(idx),
+                idx,
+                &proof,
+                root
+            ));
+            singles += proof.len() - 1;
+        }
+        assert!(multi.siblings.len() * 32 <= singles);
+
+        let mut wrong = values.clone();
+        wrong[0] = Bytes32::from(100u64);
+        assert!(!merkle::verify_multi(
+            ty, depth, &indices, &wrong, &multi, root
+        ));
+        assert!(!merkle::verify_multi(
+            ty, depth, &indices, &values, &multi, empty
+        ));
+    }
+
+    // a contiguous range shares nearly all of its siblings
+    let range: Vec<_> = (8..24).collect();
+    let multi = merkle.prove_multi(&range).unwrap();
+    assert_eq!(multi.siblings.len(), 4);
+    assert_eq!(multi.indices, range);
+
+    let multi = merkle.prove_multi(&[3, 5]).unwrap();
+    let values = [leaf(3), leaf(5)];
+    let conflicting = [leaf(3), leaf(5), leaf(4)];
+    assert!(!merkle::verify_multi(
+        ty,
+        depth,
+        &[3, 5, 5],
+        &conflicting,
+        &multi,
+        root
+    ));
+    assert!(!merkle::verify_multi(
+        ty,
+        depth,
+        &[3, 5],
+        &values[..1],
+        &multi,
+        root
+    ));
+    assert!(!merkle::verify_multi(ty, depth, &[], &[], &multi, root));
+    let mut extra = multi.clone();
+    extra.siblings.push(empty);
+    assert!(!merkle::verify_multi(
+        ty,
+        depth,
+        &[3, 5],
+        &values,
+        &extra,
+        root
+    ));
+    let mut short = multi.clone();
+    short.siblings.pop();
+    assert!(!merkle::verify_multi(
+        ty,
+        depth,
+        &[3, 5],
+        &values,
+        &short,
+        root
+    ));
+
+    // a proof claiming a shallower tree, with interior nodes passed off as leaves
+    let pairs = merkle.prove_multi(&[0, 1, 2, 3]).unwrap();
+    let interior = |idx: usize| Merkle::new(ty, vec![leaf(2 * idx), leaf(2 * idx + 1)]).root();
+    let shallow = merkle::MultiProof {
+        depth: depth - 1,
+        indices: vec![0, 1],
+        siblings: pairs.siblings,
+    };
+    let nodes = [interior(0), interior(1)];
+    assert!(!merkle::verify_multi(
+        ty,
+        depth,
+        &[0, 1],
+        &nodes,
+        &shallow,
+        root
+    ));
+    assert!(merkle::verify_multi(
+        ty,
+        depth - 1,
+        &[0, 1],
+        &nodes,
+        &shallow,
+        root
+    ));
+
+    let capacity = merkle.capacity();
+    assert_eq!(
+        merkle.prove_multi(&[0, 128]),
+        Err(ProveError::OutOfRange {
+            idx: 128,
+            len: 37,
+            capacity
+        })
+    );
+}
+
+#[test]
+pub fn merkle_get_leaf() {
+    let leaves = vec![Bytes32::default(); 3];
+    let mut merkle = Merkle::new_advanced(MerkleType::Value, leaves, Bytes32::default(), 4);
+    assert_eq!(merkle.get_leaf(2), Some(Bytes32::default()));
+    assert_eq!(merkle.get_leaf(3), None);
+    assert_eq!(merkle.get_leaf(7), None);
+
+    merkle.set(1, Bytes32::from(5u64));
+    assert_eq!(merkle.get_leaf(1), Some(Bytes32::from(5u64)));
+    merkle.pop_leaf();
+    assert_eq!(merkle.get_leaf(2), None);
+    assert_eq!(Merkle::default().get_leaf(0), None);
+}
+
+#[test]
+pub fn merkle_leaves_view() {
+    let leaves: Vec<_> = (1..=5u64).map(Bytes32::from).collect();
+    let ty = MerkleType::Value;
+    let mut merkle = Merkle::new_advanced(ty, leaves.clone(), Bytes32::default(), 5);
+    assert_eq!(merkle.capacity(), 16);
+    assert_eq!(merkle.len(), 5);
+    assert_eq!(merkle.leaves(), &leaves[..]);
+
+    merkle.push_leaf(Bytes32::from(6u64));
+    merkle.truncate(3);
+    assert_eq!(merkle.capacity(), 16);
+    assert_eq!(merkle.leaves().iter().count(), merkle.len());
+    assert_eq!(merkle.leaves(), &leaves[..3]);
+
+    merkle.truncate(0);
+    assert!(merkle.is_empty());
+    assert_eq!(merkle.leaves().iter().count(), 0);
+}
+
+#[test]
+pub fn prove_errors() {
+    let merkle = Merkle::new(MerkleType::Value, vec![Bytes32::default(); 5]);
+    assert_eq!(merkle.capacity(), 8);
+    assert!(merkle.prove(4).is_ok());
+    assert_eq!(
+        merkle.prove(5),
+        Err(ProveError::OutOfRange {
+            idx: 5,
+            len: 5,
+            capacity: 8
+        })
+    );
+    assert!(merkle.prove_any(7).is_ok());
+    assert_eq!(
+        merkle.prove_any(8),
+        Err(ProveError::OutOfRange {
+            idx: 8,
+            len: 5,
+            capacity: 8
+        })
+    );
+
+    for idx in 0..6 {
+        assert_eq!(merkle.prove_opt(idx), merkle.prove(idx).ok());
+    }
+    assert_eq!(Merkle::default().prove_opt(0), None);
+}
+
+#[test]
+pub fn merkle_depth_limits() {
+    let leaves = vec![Bytes32::default(); 5];
+    let ty = MerkleType::Value;
+    let err = Merkle::try_new_advanced(ty, leaves.clone(), Bytes32::default(), 70).unwrap_err();
+    assert_eq!(
+        err,
+        MerkleError::DepthTooLarge {
+            depth: 70,
+            max: MAX_DEPTH
+        }
+    );
+
+    let merkle = Merkle::try_new_advanced(ty, leaves, Bytes32::default(), MAX_DEPTH).unwrap();
+    assert_eq!(merkle.capacity(), 1 << (MAX_DEPTH - 1));
+
+    let data = bincode::serialize(&merkle).unwrap();
+    assert_eq!(bincode::deserialize::<Merkle>(&data).unwrap(), merkle);
+
+    // a hostile snapshot claiming more depth than can be represented
+    let layers = vec![vec![Bytes32::default()]; 70];
+    let version = merkle::FORMAT_VERSION;
+    let hostile = (
+        version,
+        ty,
+        layers.clone(),
+        vec![Bytes32::default(); 70],
+        70usize,
+    );
+    let data = bincode::serialize(&hostile).unwrap();
+    let err = bincode::deserialize::<Merkle>(&data)
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("exceeds the maximum"), "{err}");
+
+    // layers that don't match their empty hashes
+    let hostile = (
+        version,
+        ty,
+        layers[..3].to_vec(),
+        vec![Bytes32::default(); 1],
+        0usize,
+    );
+    let data = bincode::serialize(&hostile).unwrap();
+    let err = bincode::deserialize::<Merkle>(&data)
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("malformed"), "{err}");
+
+    // shapes the tree's own methods would index past
+    let pair = vec![Bytes32::from(1u64), Bytes32::from(2u64)];
+    let root = Merkle::new(ty, pair.clone()).root();
+    let empty = |count| vec![Bytes32::default(); count];
+    let shapes = [
+        (vec![pair.clone(), vec![root]], empty(2), 5usize),
+        (vec![pair.clone(), vec![root], vec![root]], empty(3), 0),
+        (vec![pair.clone(), vec![root]], empty(4), 0),
+        (vec![], empty(2), 4),
+    ];
+    for (layers, empty_layers, min_depth) in shapes {
+        let hostile = (version, ty, layers, empty_layers, min_depth);
+        let data = bincode::serialize(&hostile).unwrap();
+        let err = bincode::deserialize::<Merkle>(&data)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("malformed"), "{err}");
+    }
+
+    let valid = Merkle::new_advanced(ty, pair, Bytes32::default(), 5);
+    let data = bincode::serialize(&valid).unwrap();
+    let mut merkle = bincode::deserialize::<Merkle>(&data).unwrap();
+    merkle.truncate(1);
+    merkle.pop_leaf();
+    assert!(merkle.is_empty());
+
+    let emptied = Merkle::new_advanced(ty, vec![], Bytes32::default(), 4);
+    let data = bincode::serialize(&emptied).unwrap();
+    assert_eq!(bincode::deserialize::<Merkle>(&data).unwrap(), emptied);
+}
+
+#[test]
+pub fn proof_length_matches_depth() {
+    let ty = MerkleType::Memory;
+    for (leaves, min_depth) in [(1, 0), (1, 11), (5, 0), (5, 3), (5, 11), (8, 4), (9, 28)] {
+        let hashes: Vec<_> = (0..leaves as u64).map(|i| Bytes32::from(i + 1)).collect();
+        let merkle = Merkle::new_advanced(ty, hashes.clone(), Bytes32::default(), min_depth);
+        let depth = min_depth.max(leaves.next_power_of_two().trailing_zeros() as usize + 1);
+        assert_eq!(merkle.capacity(), 1 << (depth - 1));
+
+        let capacity = merkle.capacity();
+        for idx in [0, leaves - 1, leaves, capacity - 1]
+            .into_iter()
+            .filter(|&i| i < capacity)
+        {
+            let proof = merkle.prove_any(idx).unwrap();
+            assert_eq!(
+                proof[0] as usize,
+                depth - 1,
+                "{leaves} leaves, min depth {min_depth}"
+            );
+            let leaf = hashes.get(idx).copied().unwrap_or_default();
+            let root = merkle::root_from_proof(ty, leaf, idx, &proof);
+            assert_eq!(root, Some(merkle.root()));
+        }
+    }
+}
+
+#[test]
+pub fn machine_milestone_logs() -> Result<()> {
+    capture_logs();
+    let mut mach = machine_from_wat("(module)", "logged", true)?;
+    assert!(logged("merkleized 2 modules"));
+    assert!(logged("loaded logged with 2 modules"));
+
+    mach.add_inbox_msg(InboxIdentifier::Sequencer, 3, vec![0; 8]);
+    assert!(logged("added Sequencer inbox message 3 (8 bytes)"));
+
+    let path = std::env::temp_dir().join("prover-machine-milestone-logs.bin");
+    mach.serialize_state(&path)?;
+    mach.deserialize_and_replace_state(&path)?;
+    fs::remove_file(&path)?;
+    assert!(logged("wrote machine snapshot at step 0"));
+    assert!(logged("restored machine snapshot at step 0"));
+    Ok(())
+}
+
+/// Pins the serialized layout of a merkle so snapshots stay portable across platforms.
+/// bincode always encodes `usize` as a little-endian u64, so the format doesn't depend on word size.
+#[test]
+pub fn merkle_serialization_layout() {
+    // a layout change must bump the format version and check in a new fixture
+    let expected = include_bytes!("../test-cases/merkle-v1.bin");
+    assert_eq!(merkle::FORMAT_VERSION, 1);
+
+    let ty = MerkleType::Value;
+    let leaves = vec![Bytes32::from(1u64), Bytes32::from(2u64)];
+    let merkle = Merkle::new_advanced(ty, leaves, Bytes32::default(), 3);
+    let data = bincode::serialize(&merkle).unwrap();
+    assert_eq!(hex::encode(&data), hex::encode(expected));
+    assert_eq!(bincode::deserialize::<Merkle>(expected).unwrap(), merkle);
+
+    let mut future = expected.to_vec();
+    future[0] += 1;
+    let err = bincode::deserialize::<Merkle>(&future).unwrap_err();
+    let message = err.to_string();
+    assert!(
+        message.contains("unsupported merkle format version 2"),
+        "{message}"
+    );
+}
+
+#[test]
+pub fn run_guarded_panics() {
+    use crate::ProverPanic;
+
+    let merkle = Merkle::new(MerkleType::Value, vec![Bytes32::default(); 2]);
+    let result = crate::run_guarded(|| merkle.clone().set(5, Bytes32::default()));
+    let Err(ProverPanic::IndexOutOfBounds(message)) = result else {
+        panic!("unexpected {result:?}")
+    };
+    assert!(message.contains("index 5 out of bounds"));
+
+    // the tree is untouched, so it's safe to keep using
+    assert_eq!(crate::run_guarded(|| merkle.root()), Ok(merkle.root()));
+
+    // the panic's payload classifies it, not its message
+    let corrupt = || ProverPanic::CorruptTree("Failed to prove module".into()).raise();
+    let result = crate::run_guarded(corrupt);
+    assert!(matches!(result, Err::<(), _>(ProverPanic::CorruptTree(_))));
+    let result = crate::run_guarded(|| panic!("index 5 out of bounds"));
+    assert!(matches!(result, Err::<(), _>(ProverPanic::Other(_))));
+
+    let result = crate::run_guarded(|| panic!("{}", 7));
+    assert_eq!(result, Err::<(), _>(ProverPanic::Other("7".into())));
+    let result = crate::run_guarded(|| std::panic::panic_any(7));
+    assert!(matches!(result, Err::<(), _>(ProverPanic::Other(_))));
+}
+
+#[test]
+#[cfg(feature = "rayon")]
+pub fn hash_thread_pool() {
+    // a local pool, since installing the global one would leak into every other test
+    let config = crate::ThreadPoolConfig {
+        threads: 2,
+        stack_size: Some(4 << 20),
+    };
+    let pool = utils::build_hash_thread_pool(config).unwrap();
+
+    let ty = MerkleType::Memory;
+    let leaves: Vec<Bytes32> = (0..1u64 << 12)
+        .map(|i| crypto::keccak(i.to_be_bytes()).into())
+        .collect();
+    let (parallelism, merkle) = pool.install(|| {
+        let name = std::thread::current().name().map(str::to_owned);
+        assert!(name.unwrap().starts_with("prover-hash-"));
+        (crate::hash_parallelism(), Merkle::new(ty, leaves.clone()))
+    });
+    assert_eq!(parallelism, 2);
+
+    let mut layer = leaves;
+    while layer.len() > 1 {
+        let prefix = ty.get_prefix().as_bytes();
+        let next = layer
+            .chunks(2)
+            .map(|x| crypto::keccak([prefix, &*x[0], &*x[1]].concat()));
+        layer = next.map(Into::into).collect();
+    }
+    assert_eq!(merkle.root(), layer[0]);
+}
+
+#[test]
+pub fn machine_identity_digest() -> Result<()> {
+    let prepare = |byte: u8| -> Result<Machine> {
+        let mut mach = machine_from_wat("(module)", "identity", false)?;
+        mach.add_inbox_msg(InboxIdentifier::Delayed, 1, vec![1, 2, 3]);
+        mach.add_inbox_msg(InboxIdentifier::Sequencer, 7, vec![4, 5, byte]);
+        Ok(mach)
+    };
+    let digest = prepare(6)?.identity_digest();
+    assert_eq!(prepare(6)?.identity_digest(), digest);
+    assert_ne!(prepare(0)?.identity_digest(), digest);
+
+    let mut mach = prepare(6)?;
+    let mut state = mach.get_global_state();
+    state.u64_vals[0] += 1;
+    mach.set_global_state(state);
+    assert_ne!(mach.identity_digest(), digest);
+    Ok(())
+}
+
+#[test]
+pub fn wavm_from_bytes() -> Result<()> {
+    let mach = machine_from_wat(
+        "(module (memory 1) (func (export \"main\")))",
+        "wavm",
+        false,
+    )?;
+    let path = std::env::temp_dir().join("prover-wavm-from-bytes.wavm.br");
+    mach.serialize_binary(&path)?;
+    let data = fs::read(&path)?;
+
+    let from_path = Machine::new_from_wavm(&path)?;
+    fs::remove_file(&path)?;
+    let from_bytes = Machine::new_from_wavm_bytes(&data)?;
+    assert_eq!(from_path.hash(), from_bytes.hash());
+    assert_eq!(from_path.get_modules_root(), mach.get_modules_root());
+
+    assert!(Machine::new_from_wavm_bytes(&data[..data.len() / 2]).is_err());
+
+    // modules that deserialize but break the parser's invariants are rejected too
+    let modules = brotli::decompress(&data, Dictionary::Empty).unwrap();
+    let mut modules: Vec<crate::machine::Module> = bincode::deserialize(&modules)?;
+    modules[0].internals_offset = u32::MAX;
+    let modules = bincode::serialize(&modules)?;
+    let tampered = brotli::compress(&modules, 9, 22, Dictionary::Empty).unwrap();
+    let err = Machine::new_from_wavm_bytes(&tampered).unwrap_err();
+    assert!(err.to_string().contains("internals offset"), "{err}");
+    Ok(())
+}
+
+#[cfg(feature = "step-journal")]
+#[test]
+pub fn step_journal() -> Result<()> {
+    use crate::machine::{journal_diff, StepJournal};
+
+    let machine = |offset: u32| -> Result<Machine> {
+        let wat = format!(
+            r#"
+            (module
+                (global $g (mut i32) (i32.const 0))
+                (func $f (local $i i32)
+                    (loop $l
+                        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                        (br_if $l (i32.lt_u (local.get $i) (i32.const 10))))
+                    (global.set $g (i32.add (local.get $i) (i32.const {offset}))))
+                (start $f))"#
+        );
+        let mut mach = machine_from_wat(&wat, "journal", false)?;
+        mach.start_journal();
+        Ok(mach)
+    };
+    let run = |mut mach: Machine| -> Result<(Vec<u64>, Vec<Bytes32>)> {
+        let mut hashes = vec![];
+        while !mach.is_halted() && mach.get_steps() < 500 {
+            mach.step_n(1)?;
+            hashes.push(mach.hash());
+        }
+        let journal = mach.stop_journal().unwrap();
+        assert_eq!(journal.start(), 1);
+        Ok((journal.digests().to_vec(), hashes))
+    };
+
+    let (a, hashes_a) = run(machine(5)?)?;
+    let (b, _) = run(machine(5)?)?;
+    assert!(a.len() > 10);
+    assert_eq!(journal_diff(&a, &b), None);
+
+    // a step whose result differs shows up in the journal as of the following step
+    let (c, hashes_c) = run(machine(6)?)?;
+    let diverged = hashes_a
+        .iter()
+        .zip(&hashes_c)
+        .position(|(a, c)| a != c)
+        .unwrap()
+        + 1;
+    assert_eq!(journal_diff(&a, &c), Some(diverged as u64));
+    assert_eq!(journal_diff(&a, &a[..3]), Some(3));
+
+    // a step whose memory writes differ shows up in the journal as of that step
+    let grow = |max: u32| -> Result<Machine> {
+        let wat = format!(
+            "(module (memory 1 {max}) (func $f (drop (memory.grow (i32.const 1)))) (start $f))"
+        );
+        let mut mach = machine_from_wat(&wat, "journal", false)?;
+        mach.start_journal();
+        Ok(mach)
+    };
+    let (d, hashes_d) = run(grow(1)?)?;
+    let (e, hashes_e) = run(grow(2)?)?;
+    let diverged = hashes_d.iter().zip(&hashes_e).position(|(d, e)| d != e);
+    assert!(diverged.is_some());
+    assert_eq!(journal_diff(&d, &e), diverged.map(|x| x as u64));
+
+    let mut mach = machine(5)?;
+    mach.step_n(4)?;
+    let mut flushed = vec![];
+    mach.journal_mut().unwrap().flush(&mut flushed)?;
+    assert_eq!(mach.journal_mut().unwrap().start(), 5);
+    mach.step_n(4)?;
+    mach.journal_mut().unwrap().flush(&mut flushed)?;
+    assert_eq!(StepJournal::read(flushed.as_slice())?, a[..8]);
+    Ok(())
+}
+
+#[test]
+pub fn prove_memory_word() -> Result<()> {
+    let mach = machine_from_wat(
+        r#"(module (memory 1) (data (i32.const 30) "abcdef"))"#,
+        "memory",
+        false,
+    )?;
+    let module = mach.find_module("memory")?;
+    let ty = MerkleType::Memory;
+    let leaf_hash = |leaf: &[u8]| crypto::keccak([&b"Memory leaf:"[..], leaf].concat()).into();
+
+    for (address, index, data) in [
+        (30, 0, "ab"),
+        (31, 0, "ab"),
+        (32, 1, "cdef"),
+        (40, 1, "cdef"),
+        (65535, 2047, ""),
+        (100_000, 3125, ""),
+    ] {
+        let word = mach.prove_memory_word(module, address)?;
+        assert_eq!(word.leaf_index, index);
+
+        let offset = if index == 0 { 30 } else { 0 };
+        let mut leaf = [0; 32];
+        leaf[offset..offset + data.len()].copy_from_slice(data.as_bytes());
+        assert_eq!(word.leaf, leaf, "address {address}");
+
+        let root = merkle::root_from_proof(ty, leaf_hash(&word.leaf), index, &word.proof);
+        assert_eq!(root, Some(word.memory_root));
+    }
+
+    assert!(mach.prove_memory_word(module, 1 << 32).is_err());
+    assert!(mach.prove_memory_word(module + 1, 0).is_err());
+    Ok(())
+}
+
+#[test]
+pub fn dump_and_load_memory() -> Result<()> {
+    let wat = r#"
+        (module
+            (memory 1 2)
+            (func $f
+                (if (i32.load (i32.const 0))
+                    (then unreachable)))
+            (start $f))"#;
+    let path = std::env::temp_dir().join("prover-dump-memory.bin");
+    let mut mach = machine_from_wat(wat, "dump", true)?;
+    let module = mach.find_module("dump")?;
+    let hash = mach.hash();
+    assert_eq!(mach.dump_memory(module, &path)?, 65536);
+    mach.load_memory(module, &path)?;
+    assert_eq!(mach.hash(), hash);
+
+    let mut image = std::fs::read(&path)?;
+    image[0] = 1;
+    std::fs::write(&path, &image)?;
+    mach.load_memory(module, &path)?;
+    assert_ne!(mach.hash(), hash);
+    assert_eq!(mach.hash(), {
+        let mut fresh = mach.clone();
+        fresh.stop_merkle_caching();
+        fresh.hash()
+    });
+    mach.step_n(100)?;
+    assert_eq!(mach.get_status(), MachineStatus::Errored);
+
+    let mut reference = machine_from_wat(wat, "dump", true)?;
+    reference.step_n(100)?;
+    assert_ne!(reference.get_status(), MachineStatus::Errored);
+
+    for size in [3 * 65536, 1] {
+        image.resize(size, 0);
+        std::fs::write(&path, &image)?;
+        assert!(mach.load_memory(module, &path).is_err());
+    }
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+pub fn merkleize_policies() -> Result<()> {
+    let wat = r#"
+        (module
+            (memory 1)
+            (func $f (local $i i32)
+                (loop $l
+                    (i32.store (i32.mul (local.get $i) (i32.const 36)) (local.get $i))
+                    (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                    (br_if $l (i32.lt_u (local.get $i) (i32.const 100))))
+            )
+            (start $f))"#;
+    let main = machine_from_wat(wat, "policy", false)?.find_module("policy")?;
+    let policies = [
+        MerkleizePolicy::All,
+        MerkleizePolicy::None,
+        MerkleizePolicy::Only(vec![main]),
+        MerkleizePolicy::Only(vec![0]),
+    ];
+    let mut machines = vec![];
+    for policy in policies {
+        machines.push(machine_from_wat(wat, "policy", false)?.with_merkleize_policy(policy));
+    }
+    let mut toggled = machine_from_wat(wat, "policy", true)?;
+
+    for step in [0, 5, 100, 300, 1000] {
+        let expected = machines[0].hash();
+        for mach in &mut machines {
+            assert_eq!(mach.hash(), expected, "step {step}");
+            mach.step_n(step)?;
+        }
+        assert_eq!(toggled.hash(), expected, "step {step}");
+        toggled.set_module_merkleize(main, step % 2 == 0)?;
+        toggled.step_n(step)?;
+    }
+    assert!(toggled.set_module_merkleize(100, true).is_err());
+    Ok(())
+}
+
+#[test]
+pub fn merkleize_only_is_lazy() -> Result<()> {
+    let wat = "(module (memory 1) (func $f (i32.store (i32.const 0) (i32.const 1))) (start $f))";
+    let main = machine_from_wat(wat, "lazy", false)?.find_module("lazy")?;
+    let libraries: Vec<_> = (0..main).collect();
+
+    // flushing modules mustn't rehash the uncached main memory on every step
+    let policy = MerkleizePolicy::Only(libraries.clone());
+    let mut mach = machine_from_wat(wat, "lazy", false)?.with_merkleize_policy(policy);
+    mach.step_n(20)?;
+    assert!(libraries.iter().all(|&x| mach.is_module_merkleized(x)));
+    assert!(!mach.is_module_merkleized(main));
+    assert!(!mach.is_modules_merkle_cached());
+
+    mach.set_module_merkleize(main, true)?;
+    assert!(mach.is_modules_merkle_cached());
+    mach.set_module_merkleize(libraries[0], false)?;
+    assert!(!mach.is_modules_merkle_cached());
+
+    let all: Vec<_> = (0..=main).map(|x| x.to_string()).collect();
+    let policy = all.join(",").parse()?;
+    let mach = machine_from_wat(wat, "lazy", false)?.with_merkleize_policy(policy);
+    assert!(mach.is_modules_merkle_cached());
+    assert_eq!("none".parse::<MerkleizePolicy>()?, MerkleizePolicy::None);
+    assert_eq!("all".parse::<MerkleizePolicy>()?, MerkleizePolicy::All);
+    assert!("1,x".parse::<MerkleizePolicy>().is_err());
+    Ok(())
+}
+
+#[test]
+pub fn step_n_cancellable() -> Result<()> {
+    let wat = r#"
+        (module
+            (func $f (local $i i32)
+                (loop $l
+                    (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                    (br_if $l (i32.ne (local.get $i) (i32.const -1))))
+            )
+            (start $f))"#;
+    let mut mach = machine_from_wat(wat, "cancel", false)?;
+    let token = CancelToken::with_check_interval(1000);
+    let outcome = mach.step_n_cancellable(2500, &token)?;
+    assert_eq!(outcome, StepOutcome::Completed);
+
+    // a cancellation from another thread stops the machine at the next check
+    let canceller = token.clone();
+    let thread = std::thread::spawn(move || canceller.cancel());
+    thread.join().unwrap();
+    let outcome = mach.step_n_cancellable(u64::MAX, &token)?;
+    assert_eq!(outcome, StepOutcome::Cancelled { steps: 0 });
+    let mut reference = machine_from_wat(wat, "cancel", false)?;
+    reference.step_n(2500)?;
+    assert_eq!(mach.get_steps(), reference.get_steps());
+    assert_eq!(mach.hash(), reference.hash());
+
+    // resuming with a fresh token picks up where it left off
+    let outcome = mach.step_n_cancellable(500, &CancelToken::new())?;
+    assert_eq!(outcome, StepOutcome::Completed);
+    reference.step_n(500)?;
+    assert_eq!(mach.hash(), reference.hash());
+
+    let outcome = mach.step_n_cancellable(500, &token)?;
+    assert_eq!(outcome, StepOutcome::Cancelled { steps: 0 });
+    assert_eq!(mach.get_steps(), 3000);
+    Ok(())
+}
+
+#[test]
+pub fn machine_checkpoints() -> Result<()> {
+    let wat = r#"
+        (module
+            (func $f (local $i i32)
+                (loop $l
+                    (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                    (br_if $l (i32.lt_u (local.get $i) (i32.const 10))))
+            )
+            (start $f))"#;
+    let mut mach = machine_from_wat(wat, "checkpoints", false)?;
+    mach.enable_checkpoints(3, 2);
+    mach.step_n(2)?;
+    assert_eq!(mach.checkpoints().count(), 0);
+    mach.step_n(8)?;
+
+    let steps: Vec<_> = mach.checkpoints().map(|x| x.step).collect();
+    assert_eq!(steps, vec![6, 9]);
+    for checkpoint in mach.checkpoints() {
+        let mut reference = machine_from_wat(wat, "checkpoints", false)?;
+        reference.step_n(checkpoint.step)?;
+        assert_eq!(reference.hash(), checkpoint.hash);
+    }
+
+    assert_eq!(mach.nearest_checkpoint(5), None);
+    assert_eq!(mach.nearest_checkpoint(8).map(|x| x.step), Some(6));
+    assert_eq!(mach.nearest_checkpoint(100).map(|x| x.step), Some(9));
+    Ok(())
+}
+
+#[test]
+pub fn reverse_step() -> Result<()> {
+    let wat = r#"
+        (module
+            (func $f (local $i i32)
+                (loop $l
+                    (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                    (br_if $l (i32.lt_u (local.get $i) (i32.const 5000))))
+            )
+            (start $f))"#;
+    let mut mach = machine_from_wat(wat, "reverse", false)?;
+    mach.enable_reverse_stepping(1000, 16);
+    mach.step_n(10_000)?;
+    assert_eq!(mach.get_steps(), 10_000);
+    mach.reverse_step_n(1_500)?;
+    assert_eq!(mach.get_steps(), 8_500);
+
+    let mut reference = machine_from_wat(wat, "reverse", false)?;
+    reference.step_n(8_500)?;
+    assert_eq!(mach.hash(), reference.hash());
+
+    // stepping forward again matches too, re-recording the dropped checkpoints
+    mach.step_n(1_500)?;
+    reference.step_n(1_500)?;
+    assert_eq!(mach.hash(), reference.hash());
+    assert_eq!(mach.checkpoints().last().map(|x| x.step), Some(10_000));
+
+    assert!(mach.reverse_step_n(20_000).is_err());
+    mach.disable_checkpoints();
+    assert!(mach.reverse_step_n(1).is_err());
+    Ok(())
+}
+
+#[test]
+pub fn reverse_step_keeps_preimage_stats() -> Result<()> {
+    let preimages = [vec![1; 40], vec![2; 70]];
+    let hashes: Vec<Bytes32> = preimages.iter().map(|p| crypto::keccak(p).into()).collect();
+
+    // each read overwrites the hash with the preimage, so the hash is stored before every read
+    let read = |hash: &Bytes32| {
+        let mut code = String::new();
+        for (i, word) in hash.chunks(8).enumerate() {
+            let word = u64::from_le_bytes(word.try_into().unwrap());
+            code += &format!("(i64.store (i32.const {}) (i64.const {word}))\n", i * 8);
+        }
+        code + "(drop (call $read (i32.const 0) (i32.const 0)))\n"
+    };
+    let wat = format!(
+        r#"
+        (module
+            (import "env" "wavm_read_keccak_256_preimage" (func $read (param i32 i32) (result i32)))
+            (memory 1)
+            (func $f (local $i i32)
+                (loop $l
+                    {}{}
+                    (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                    (br_if $l (i32.lt_u (local.get $i) (i32.const 100))))
+            )
+            (start $f))"#,
+        read(&hashes[0]),
+        read(&hashes[1]),
+    );
+    let resolver = Arc::new(move |_, _, hash| {
+        let index = hashes.iter().position(|h| *h == hash)?;
+        Some(CBytes::from(preimages[index].as_slice()))
+    }) as PreimageResolver;
+
+    let mut mach = hostio_machine_from_wat(&wat, "preimages", HashMap::default(), resolver)?;
+    mach.enable_reverse_stepping(100, 16);
+    mach.step_n(1_000)?;
+    let stats = mach.stats();
+    assert!(stats.preimage.resolutions > 0);
+
+    // replaying from a snapshot re-resolves the reads it passes over
+    mach.reverse_step_n(50)?;
+    assert_eq!(mach.get_steps(), 950);
+    assert_eq!(mach.stats(), stats);
+    Ok(())
+}
+
+#[test]
+pub fn prove_with_value() {
+    let ty = MerkleType::Value;
+    let leaves: Vec<_> = (1..=5u64).map(Bytes32::from).collect();
+    let mut merkle = Merkle::new(ty, leaves.clone());
+    let root = merkle.root();
+
+    let (leaf, proof) = merkle.prove_with_value(3).unwrap();
+    assert_eq!(leaf, leaves[3]);
+    assert_eq!(merkle::root_from_proof(ty, leaf, 3, &proof), Some(root));
+
+    // the pair stays consistent with the root it was made against
+    merkle.set(3, Bytes32::default());
+    assert_ne!(merkle.root(), root);
+    assert_eq!(merkle::root_from_proof(ty, leaf, 3, &proof), Some(root));
+
+    let (leaf, proof) = merkle.prove_with_value(3).unwrap();
+    let root = merkle.root();
+    assert_eq!(merkle::root_from_proof(ty, leaf, 3, &proof), Some(root));
+    assert_eq!(
+        merkle.prove_with_value(5).unwrap_err(),
+        merkle.prove(5).unwrap_err()
+    );
+}
+
+#[cfg(feature = "work-accounting")]
+#[test]
+pub fn work_report() -> Result<()> {
+    use crate::machine::{WorkCategory, WorkReport};
+
+    let run = || -> Result<WorkReport> {
+        let wat = r#"
+            (module
+                (memory 1)
+                (func $f (local $i i32)
+                    (loop $l
+                        (i32.store (local.get $i) (local.get $i))
+                        (local.set $i (i32.add (local.get $i) (i32.const 4)))
+                        (br_if $l (i32.lt_u (local.get $i) (i32.const 40))))
+                )
+                (start $f))"#;
+        let mut mach = machine_from_wat(wat, "work", false)?;
+        assert_eq!(mach.work_report(), None);
+        mach.start_work_accounting();
+        mach.step_n(60)?;
+        Ok(mach.work_report().unwrap())
+    };
+    let report = run()?;
+    assert_eq!(report, run()?);
+    assert_eq!(report.steps, 60);
+
+    let total: u64 = report.per_category.iter().map(|(_, cost)| cost).sum();
+    assert_eq!(total, report.weighted_cost);
+    let memory = &report.per_category[WorkCategory::Memory as usize];
+    assert_eq!(memory.0, "Memory");
+    assert!(memory.1 > 0);
+    Ok(())
+}
+
+#[test]
+pub fn inbox_position() -> Result<()> {
+    let mut mach = machine_from_wat("(module)", "inbox", false)?;
+    assert_eq!(mach.inbox_position(), InboxPosition::default());
+
+    let mut state = mach.get_global_state();
+    state.u64_vals = [3, 17];
+    mach.set_global_state(state);
+    let position = InboxPosition {
+        sequencer_consumed: 3,
+        pos_in_current: 17,
+    };
+    assert_eq!(mach.inbox_position(), position);
+    assert_eq!(mach.clone().inbox_position(), position);
+
+    let path = std::env::temp_dir().join("prover-inbox-position.bin");
+    mach.serialize_state(&path)?;
+    let mut restored = machine_from_wat("(module)", "inbox", false)?;
+    restored.deserialize_and_replace_state(&path)?;
+    fs::remove_file(&path)?;
+    assert_eq!(restored.inbox_position(), position);
+    Ok(())
+}
+
+#[test]
+pub fn inbox_position_advances() -> Result<()> {
+    // reads the current message a word at a time, then moves on to the next
+    let wat = r#"
+        (module
+            (import "env" "wavm_get_globalstate_u64" (func $get (param i32) (result i64)))
+            (import "env" "wavm_set_globalstate_u64" (func $set (param i32 i64)))
+            (import "env" "wavm_read_inbox_message" (func $read (param i64 i32 i32) (result i32)))
+            (memory 1)
+            (func $f (local $len i32)
+                (loop $l
+                    (local.set $len
+                        (call $read
+                            (call $get (i32.const 0))
+                            (i32.const 0)
+                            (i32.wrap_i64 (call $get (i32.const 1)))))
+                    (call $set
+                        (i32.const 1)
+                        (i64.add (call $get (i32.const 1)) (i64.extend_i32_u (local.get $len))))
+                    (br_if $l (local.get $len)))
+                (call $set (i32.const 0) (i64.add (call $get (i32.const 0)) (i64.const 1)))
+                (call $set (i32.const 1) (i64.const 0)))
+            (start $f))"#;
+    let mut inbox = HashMap::default();
+    inbox.insert((InboxIdentifier::Sequencer, 3), vec![7; 40]);
+    let resolver = get_empty_preimage_resolver();
+    let mut mach = hostio_machine_from_wat(wat, "inbox", inbox, resolver)?;
+    let mut state = mach.get_global_state();
+    state.u64_vals = [3, 0];
+    mach.set_global_state(state);
+
+    let mut positions = vec![mach.inbox_position()];
+    while !mach.is_halted() && mach.get_steps() < 1_000 {
+        mach.step_n(1)?;
+        if positions.last() != Some(&mach.inbox_position()) {
+            positions.push(mach.inbox_position());
+        }
+    }
+    assert_ne!(mach.get_status(), MachineStatus::Errored);
+
+    let position = |sequencer_consumed, pos_in_current| InboxPosition {
+        sequencer_consumed,
+        pos_in_current,
+    };
+    let expected = [(3, 0), (3, 32), (3, 40), (4, 40), (4, 0)];
+    let expected: Vec<_> = expected.into_iter().map(|(a, b)| position(a, b)).collect();
+    assert_eq!(positions, expected);
+    Ok(())
+}
+
+#[test]
+pub fn verify_memory_word() -> Result<()> {
+    let mach = machine_from_wat(
+        r#"(module (memory 1) (data (i32.const 64) "raw bytes"))"#,
+        "verify",
+        false,
+    )?;
+    let module = mach.find_module("verify")?;
+    let empty = crypto::keccak([&b"Memory leaf:"[..], &[0; 32][..]].concat());
+    assert_eq!(crate::memory_leaf_hash(&[0; 32]), Bytes32(empty));
+
+    let mut data = [0; 32];
+    data[..9].copy_from_slice(b"raw bytes");
+    for address in [64, 70, 95] {
+        let word = mach.prove_memory_word(module, address)?;
+        assert_eq!(word.leaf, data);
+        assert!(crate::verify_memory_word(
+            word.memory_root,
+            address,
+            &data,
+            &word.proof
+        ));
+        assert!(!crate::verify_memory_word(
+            word.memory_root,
+            address + 32,
+            &data,
+            &word.proof
+        ));
+
+        let mut tampered = data;
+        tampered[0] ^= 1;
+        assert!(!crate::verify_memory_word(
+            word.memory_root,
+            address,
+            &tampered,
+            &word.proof
+        ));
+    }
+    Ok(())
+}
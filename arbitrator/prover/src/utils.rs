@@ -3,15 +3,30 @@
 
 #[cfg(feature = "native")]
 use crate::kzg::ETHEREUM_KZG_SETTINGS;
+#[cfg(feature = "native")]
+use arbutil::Bytes32;
 use arbutil::PreimageType;
 #[cfg(feature = "native")]
 use c_kzg::{Blob, KzgCommitment};
 use digest::Digest;
+#[cfg(feature = "native")]
+use eyre::{bail, WrapErr};
 use eyre::{eyre, Result};
+#[cfg(feature = "native")]
+use fnv::FnvHashMap as HashMap;
 use serde::{Deserialize, Serialize};
 use sha2::Sha256;
 use sha3::Keccak256;
-use std::{borrow::Borrow, convert::TryInto, fmt, fs::File, io::Read, ops::Deref, path::Path};
+use std::{
+    borrow::Borrow,
+    convert::TryInto,
+    fmt,
+    fs::File,
+    io::{ErrorKind, Read},
+    ops::Deref,
+    panic::{self, UnwindSafe},
+    path::Path,
+};
 use wasmparser::{RefType, TableType};
 
 /// A Vec<u8> allocated with libc::malloc
@@ -206,3 +221,218 @@ pub fn hash_preimage(preimage: &[u8], ty: PreimageType) -> Result<[u8; 32]> {
         }
     }
 }
+
+/// Loads a directory of preimage files, each named by the hex hash of its contents.
+/// When `verify` is set, each file is rehashed and rejected if it doesn't match its name.
+#[cfg(feature = "native")]
+pub fn preimages_from_dir(
+    dir: &Path,
+    ty: PreimageType,
+    verify: bool,
+) -> Result<HashMap<Bytes32, CBytes>> {
+    let mut preimages = HashMap::default();
+    let mut invalid = vec![];
+
+    let entries = std::fs::read_dir(dir).wrap_err_with(|| format!("failed to read {dir:?}"))?;
+    for entry in entries {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let Ok(hash) = Bytes32::from_hex(&name) else {
+            invalid.push(name.into_owned());
+            continue;
+        };
+        let data = file_bytes(&path).wrap_err_with(|| format!("failed to read {path:?}"))?;
+        if verify {
+            let have = Bytes32(hash_preimage(&data, ty)?);
+            if have != hash {
+                bail!("preimage file {path:?} rehashes to {have}");
+            }
+        }
+        preimages.insert(hash, data.as_slice().into());
+    }
+    if !invalid.is_empty() {
+        invalid.sort();
+        bail!(
+            "preimage files in {dir:?} not named by hash: {}",
+            invalid.join(", ")
+        );
+    }
+    Ok(preimages)
+}
+
+/// Reads a preimage file as consumed by the prover's `--preimages` flag.
+/// Each record is a type byte, a little-endian u64 length, and then the preimage itself.
+/// Errors name the record and the byte offset at which it starts.
+#[cfg(feature = "native")]
+pub fn read_preimages(
+    mut reader: impl Read,
+) -> Result<HashMap<PreimageType, HashMap<Bytes32, CBytes>>> {
+    let mut preimages: HashMap<PreimageType, HashMap<Bytes32, CBytes>> = HashMap::default();
+    let mut offset = 0;
+    for record in 0.. {
+        let context = || format!("preimage record {record} at byte offset {offset}");
+
+        let mut ty_buf = [0u8; 1];
+        match reader.read_exact(&mut ty_buf) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e).wrap_err_with(context),
+        }
+        let Ok(ty) = PreimageType::try_from(ty_buf[0]) else {
+            bail!("{}: unknown preimage type {}", context(), ty_buf[0]);
+        };
+
+        let mut size_buf = [0u8; 8];
+        reader
+            .read_exact(&mut size_buf)
+            .wrap_err_with(|| format!("{}: truncated length", context()))?;
+        let size = u64::from_le_bytes(size_buf);
+        let mut buf = vec![];
+        let read = reader.by_ref().take(size).read_to_end(&mut buf);
+        let read = read.wrap_err_with(context)? as u64;
+        if read != size {
+            bail!("{}: expected {size} bytes of data, got {read}", context());
+        }
+
+        let hash = hash_preimage(&buf, ty).wrap_err_with(context)?;
+        preimages
+            .entry(ty)
+            .or_default()
+            .insert(hash.into(), buf.as_slice().into());
+        offset += 9 + size;
+    }
+    Ok(preimages)
+}
+
+/// Configures the thread pool used for parallel merkle and machine hashing.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ThreadPoolConfig {
+    /// The number of worker threads, or 0 to use one per CPU.
+    pub threads: usize,
+    /// The stack size for each worker, or the rayon default if `None`.
+    pub stack_size: Option<usize>,
+}
+
+#[cfg(feature = "rayon")]
+static HASH_THREAD_POOL: once_cell::sync::OnceCell<rayon::ThreadPool> =
+    once_cell::sync::OnceCell::new();
+
+/// Builds a dedicated pool for all parallel hashing, so that it doesn't compete with the
+/// embedder's use of the global rayon pool. May only be called once, before hashing begins.
+pub fn set_hash_thread_pool(config: ThreadPoolConfig) -> Result<()> {
+    #[cfg(feature = "rayon")]
+    {
+        let pool = build_hash_thread_pool(config)?;
+        HASH_THREAD_POOL
+            .set(pool)
+            .map_err(|_| eyre!("hash thread pool already set"))
+    }
+
+    #[cfg(not(feature = "rayon"))]
+    {
+        let _ = config;
+        Err(eyre!("prover was built without the rayon feature"))
+    }
+}
+
+/// Builds a pool as [`set_hash_thread_pool`] would, without installing it.
+#[cfg(feature = "rayon")]
+pub(crate) fn build_hash_thread_pool(config: ThreadPoolConfig) -> Result<rayon::ThreadPool> {
+    let mut builder = rayon::ThreadPoolBuilder::new()
+        .num_threads(config.threads)
+        .thread_name(|i| format!("prover-hash-{i}"));
+    if let Some(size) = config.stack_size {
+        builder = builder.stack_size(size);
+    }
+    Ok(builder.build()?)
+}
+
+/// The number of threads hashing is spread across.
+pub fn hash_parallelism() -> usize {
+    #[cfg(feature = "rayon")]
+    match HASH_THREAD_POOL.get() {
+        Some(pool) => pool.current_num_threads(),
+        None => rayon::current_num_threads(),
+    }
+
+    #[cfg(not(feature = "rayon"))]
+    1
+}
+
+/// Runs parallel hashing work in the configured pool, or the global pool if none was set.
+pub(crate) fn with_hash_pool<R: Send>(work: impl FnOnce() -> R + Send) -> R {
+    #[cfg(feature = "rayon")]
+    if let Some(pool) = HASH_THREAD_POOL.get() {
+        return pool.install(work);
+    }
+    work()
+}
+
+/// A panic caught by [`run_guarded`]. The prover's own panic sites raise one of these as the
+/// panic payload, so it's recovered intact; any other panic becomes [`ProverPanic::Other`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProverPanic {
+    /// A leaf, local, global, or other index was past the end of what it indexed.
+    IndexOutOfBounds(String),
+    /// A merkle tree was malformed or couldn't prove an element it should contain.
+    CorruptTree(String),
+    Other(String),
+}
+
+impl ProverPanic {
+    /// Panics with this as the payload, for [`run_guarded`] to downcast.
+    pub(crate) fn raise(self) -> ! {
+        // the panic hook can't print a payload that isn't a string
+        eprintln!("{self}");
+        panic::panic_any(self)
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            Self::IndexOutOfBounds(x) | Self::CorruptTree(x) | Self::Other(x) => x,
+        }
+    }
+}
+
+impl fmt::Display for ProverPanic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::IndexOutOfBounds(x) => {
+                write!(f, "prover panicked on an out of bounds index: {x}")
+            }
+            Self::CorruptTree(x) => write!(f, "prover panicked on a corrupt merkle tree: {x}"),
+            Self::Other(x) => write!(f, "prover panicked: {x}"),
+        }
+    }
+}
+
+impl std::error::Error for ProverPanic {}
+
+/// Runs `f`, converting a panic into a [`ProverPanic`] for services that embed the prover
+/// and can't afford to unwind. The panic hook still runs, so the panic is logged as usual.
+///
+/// Merkle reads and proofs are safe to retry after catching, as is [`Merkle::set`], which
+/// panics before changing anything. A [`Machine`] that panicked mid-step may be partially
+/// updated and should be discarded or restored from a snapshot rather than stepped again.
+///
+/// [`Merkle::set`]: crate::merkle::Merkle::set
+/// [`Machine`]: crate::Machine
+pub fn run_guarded<T>(f: impl FnOnce() -> T + UnwindSafe) -> Result<T, ProverPanic> {
+    panic::catch_unwind(f).map_err(|payload| {
+        let payload = match payload.downcast::<ProverPanic>() {
+            Ok(panic) => return *panic,
+            Err(payload) => payload,
+        };
+        let message = match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => match payload.downcast::<&'static str>() {
+                Ok(message) => message.to_string(),
+                Err(_) => "non-string panic payload".to_owned(),
+            },
+        };
+        ProverPanic::Other(message)
+    })
+}